@@ -0,0 +1,226 @@
+// this file audits a vault by recomputing BLAKE3 from the stored nonces and comparing it to the stored hashes
+use crate::hash_generator::generate_hash;
+use crate::progress_tracker::ProgressTracker;
+use crate::{vault, HASH_SIZE, NONCE_SIZE, RECORD_SIZE};
+use rand::seq::index;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const AUDIT_CHUNK_RECORDS: u64 = 32 * 1024; // records recomputed per parallel task in a full audit
+const MAX_REPORTED_MISMATCHES: usize = 20;
+
+// how much of a vault an audit recomputes
+#[derive(Debug, Clone, Copy)]
+pub enum AuditMode {
+    Full,
+    Sample { sample_size: u64, confidence: f64 },
+}
+
+// a record whose stored hash is not BLAKE3 of its stored nonce
+#[derive(Debug, Clone, Copy)]
+pub struct Mismatch {
+    pub offset: u64, // in bytes
+    pub nonce: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    pub records_checked: u64,
+    pub padding_skipped: u64,
+    pub total_mismatches: u64,
+    pub mismatches: Vec<Mismatch>, // the first MAX_REPORTED_MISMATCHES, by offset
+    pub num_records: u64,
+    pub confidence: Option<f64>, // only set for a sampled audit
+    pub duration: f64,           // in seconds
+}
+
+impl AuditReport {
+    pub fn is_valid(&self) -> bool {
+        self.total_mismatches == 0
+    }
+
+    // for a clean sample: the corruption rate that, at the chosen confidence, would still most likely have shown up
+    // (exact binomial bound for zero failures: 1 - (1 - confidence)^(1 / n))
+    pub fn max_corruption_rate(&self) -> Option<f64> {
+        let confidence = self.confidence?;
+        if self.total_mismatches > 0 || self.records_checked == 0 {
+            return None;
+        }
+        Some(1.0 - (1.0 - confidence).powf(1.0 / self.records_checked as f64))
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Audited {} of {} records in {:.2} seconds ({} padding records skipped)",
+            self.records_checked, self.num_records, self.duration, self.padding_skipped
+        );
+        println!("Hash mismatches: {}", self.total_mismatches);
+        for mismatch in &self.mismatches {
+            println!(
+                "  byte offset {} (record {}), nonce {}",
+                mismatch.offset,
+                mismatch.offset / RECORD_SIZE as u64,
+                mismatch.nonce
+            );
+        }
+        if self.total_mismatches as usize > self.mismatches.len() {
+            println!(
+                "  ... and {} more",
+                self.total_mismatches as usize - self.mismatches.len()
+            );
+        }
+
+        if let Some(confidence) = self.confidence {
+            match self.max_corruption_rate() {
+                Some(rate) => println!(
+                    "With {:.1}% confidence, fewer than {:.4}% of records (about {:.0}) are corrupt",
+                    confidence * 100.0,
+                    rate * 100.0,
+                    (rate * self.num_records as f64).ceil()
+                ),
+                None if self.records_checked > 0 => println!(
+                    "Estimated corrupt records: {:.0} ({:.4}% of the sample)",
+                    self.total_mismatches as f64 / self.records_checked as f64
+                        * self.num_records as f64,
+                    self.total_mismatches as f64 / self.records_checked as f64 * 100.0
+                ),
+                None => {}
+            }
+        }
+    }
+}
+
+pub fn audit(path: &Path, mode: AuditMode, show_progress: bool) -> io::Result<AuditReport> {
+    match mode {
+        AuditMode::Full => audit_full(path, show_progress),
+        AuditMode::Sample {
+            sample_size,
+            confidence,
+        } => audit_sample(path, sample_size, confidence),
+    }
+}
+
+// recomputes the hash of every record, spread over the rayon pool
+pub fn audit_full(path: &Path, show_progress: bool) -> io::Result<AuditReport> {
+    let (file, footer) = vault::open_vault(path)?;
+    let start_audit = Instant::now();
+    let num_records = footer.num_records;
+    let num_chunks = num_records.div_ceil(AUDIT_CHUNK_RECORDS);
+
+    let tracker = if show_progress {
+        let tracker =
+            ProgressTracker::new(num_records, num_chunks as usize, Duration::from_secs(2));
+        tracker.set_stage("[AUDIT]");
+        Some(tracker)
+    } else {
+        None
+    };
+
+    let chunk_reports = (0..num_chunks)
+        .into_par_iter()
+        .map(|chunk| {
+            let first_record = chunk * AUDIT_CHUNK_RECORDS;
+            let records = AUDIT_CHUNK_RECORDS.min(num_records - first_record);
+            let mut buffer = vec![0u8; records as usize * RECORD_SIZE];
+            file.read_exact_at(&mut buffer, first_record * RECORD_SIZE as u64)?;
+
+            let mut report = AuditReport::default();
+            for (i, record) in buffer.chunks_exact(RECORD_SIZE).enumerate() {
+                let offset = (first_record + i as u64) * RECORD_SIZE as u64;
+                check_record(record, offset, footer.prefix_size as usize, &mut report);
+            }
+
+            if let Some(ref tracker) = tracker {
+                tracker.update_records_processed(records);
+                tracker.increment_flushes(1);
+            }
+            Ok(report)
+        })
+        .collect::<io::Result<Vec<AuditReport>>>()?;
+
+    let mut report = AuditReport {
+        num_records,
+        ..Default::default()
+    };
+    for chunk_report in chunk_reports {
+        merge_into(&mut report, chunk_report);
+    }
+    report.duration = start_audit.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+// recomputes the hash of `sample_size` distinct records picked at random
+pub fn audit_sample(path: &Path, sample_size: u64, confidence: f64) -> io::Result<AuditReport> {
+    let (file, footer) = vault::open_vault(path)?;
+    let start_audit = Instant::now();
+    let num_records = footer.num_records;
+    let sample_size = sample_size.min(num_records) as usize;
+
+    let positions = index::sample(&mut rand::thread_rng(), num_records as usize, sample_size);
+    let sample_reports = positions
+        .into_vec()
+        .into_par_iter()
+        .map(|position| {
+            let offset = position as u64 * RECORD_SIZE as u64;
+            let mut report = AuditReport::default();
+            check_record(
+                &read_record(&file, offset)?,
+                offset,
+                footer.prefix_size as usize,
+                &mut report,
+            );
+            Ok(report)
+        })
+        .collect::<io::Result<Vec<AuditReport>>>()?;
+
+    let mut report = AuditReport {
+        num_records,
+        confidence: Some(confidence),
+        ..Default::default()
+    };
+    for sample_report in sample_reports {
+        merge_into(&mut report, sample_report);
+    }
+    report.duration = start_audit.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+fn read_record(file: &File, offset: u64) -> io::Result<[u8; RECORD_SIZE]> {
+    let mut record = [0u8; RECORD_SIZE];
+    file.read_exact_at(&mut record, offset)?;
+    Ok(record)
+}
+
+fn check_record(record: &[u8], offset: u64, prefix_size: usize, report: &mut AuditReport) {
+    // zero padding has no nonce behind it
+    if record.iter().all(|&byte| byte == 0) {
+        report.padding_skipped += 1;
+        return;
+    }
+
+    let nonce = record[..NONCE_SIZE]
+        .iter()
+        .fold(0u64, |acc, &b| acc * 256 + b as u64);
+    let (_, expected) = generate_hash(nonce, prefix_size);
+
+    report.records_checked += 1;
+    if record[NONCE_SIZE..NONCE_SIZE + HASH_SIZE] != expected.hash {
+        report.total_mismatches += 1;
+        report.mismatches.push(Mismatch { offset, nonce });
+    }
+}
+
+fn merge_into(report: &mut AuditReport, other: AuditReport) {
+    report.records_checked += other.records_checked;
+    report.padding_skipped += other.padding_skipped;
+    report.total_mismatches += other.total_mismatches;
+    report.mismatches.extend(other.mismatches);
+    report
+        .mismatches
+        .sort_unstable_by_key(|mismatch| mismatch.offset);
+    report.mismatches.truncate(MAX_REPORTED_MISMATCHES);
+}
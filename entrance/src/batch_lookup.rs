@@ -0,0 +1,249 @@
+// this file resolves many prefix lookups against one open vault and reports their latencies
+use crate::lookup::{self, hash_to_string, nonce_to_decimal, SearchStrategy};
+use crate::prefix::{Prefix, PrefixFormat};
+use crate::report::{self, OutputFormat};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// the outcome of a single query line
+struct QueryResult {
+    query: String,
+    outcome: Result<(Vec<(u64, String)>, usize), String>, // matching (nonce, hash hex) pairs and seeks, or why it failed
+    latency: Duration,
+}
+
+#[derive(Serialize, Default)]
+struct BatchRow {
+    query: String,
+    status: &'static str,
+    matches: usize,
+    seeks: usize,
+    latency_us: f64,
+    records: String, // nonce:hash pairs separated by ','
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BatchSummary {
+    vault: String,
+    num_queries: usize,
+    found: usize,
+    missed: usize,
+    invalid: usize,
+    duration_s: f64,
+    queries_per_s: f64,
+    latency_min_us: Option<f64>,
+    latency_mean_us: Option<f64>,
+    latency_p50_us: Option<f64>,
+    latency_p90_us: Option<f64>,
+    latency_p99_us: Option<f64>,
+    latency_max_us: Option<f64>,
+}
+
+pub struct LatencyStats {
+    pub count: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub fn from_latencies(mut latencies: Vec<Duration>) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        let count = latencies.len();
+        let percentile =
+            |p: f64| latencies[((count as f64 * p).ceil() as usize).clamp(1, count) - 1];
+        Some(Self {
+            count,
+            min: latencies[0],
+            mean: latencies.iter().sum::<Duration>() / count as u32,
+            p50: percentile(0.50),
+            p90: percentile(0.90),
+            p99: percentile(0.99),
+            max: latencies[count - 1],
+        })
+    }
+
+    pub fn print(&self) {
+        eprintln!(
+            "Latency over {} queries: min {:?}, mean {:?}, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.count, self.min, self.mean, self.p50, self.p90, self.p99, self.max
+        );
+    }
+}
+
+// reads one prefix per line (blank lines and lines starting with '#' are skipped); "<prefix> <N>" matches only N bits
+pub fn read_queries(source: &str) -> io::Result<Vec<String>> {
+    let reader: Box<dyn BufRead> = if source == "-" {
+        Box::new(io::BufReader::new(io::stdin()))
+    } else {
+        Box::new(io::BufReader::new(std::fs::File::open(source)?))
+    };
+
+    let mut queries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            queries.push(line.to_string());
+        }
+    }
+    Ok(queries)
+}
+
+// answers every query in parallel and writes one tab-separated line per query, in input order, to stdout
+// (or one row per query in the chosen output format)
+pub fn lookup_batch(
+    path: &Path,
+    queries: &[String],
+    format: PrefixFormat,
+    default_bits: Option<usize>,
+    strategy: SearchStrategy,
+    output_format: OutputFormat,
+) -> io::Result<()> {
+    let (file, footer) = lookup::open_sorted_vault(path)?;
+
+    let start_batch = Instant::now();
+    let results: Vec<QueryResult> = queries
+        .par_iter()
+        .map(|query| {
+            let start_query = Instant::now();
+            let outcome = parse_query(query, format, default_bits).and_then(|prefix| {
+                let (records, seeks) = lookup::find_by_prefix(&file, &footer, &prefix, strategy)
+                    .map_err(|e| e.to_string())?;
+                let records = records
+                    .iter()
+                    .map(|record| {
+                        (
+                            nonce_to_decimal(&record.nonce),
+                            hash_to_string(&record.hash),
+                        )
+                    })
+                    .collect();
+                Ok((records, seeks))
+            });
+            QueryResult {
+                query: query.clone(),
+                outcome,
+                latency: start_query.elapsed(),
+            }
+        })
+        .collect();
+    let duration = start_batch.elapsed();
+
+    let rows = results.iter().map(batch_row).collect::<Vec<BatchRow>>();
+    let found = rows.iter().filter(|row| row.status == "found").count();
+    let invalid = rows.iter().filter(|row| row.status == "error").count();
+    let stats = LatencyStats::from_latencies(results.iter().map(|result| result.latency).collect());
+
+    if !output_format.is_table() {
+        let micros = |latency: fn(&LatencyStats) -> Duration| {
+            stats
+                .as_ref()
+                .map(|stats| latency(stats).as_secs_f64() * 1e6)
+        };
+        let summary = BatchSummary {
+            vault: path.display().to_string(),
+            num_queries: rows.len(),
+            found,
+            missed: rows.len() - found - invalid,
+            invalid,
+            duration_s: duration.as_secs_f64(),
+            queries_per_s: rows.len() as f64 / duration.as_secs_f64(),
+            latency_min_us: micros(|stats| stats.min),
+            latency_mean_us: micros(|stats| stats.mean),
+            latency_p50_us: micros(|stats| stats.p50),
+            latency_p90_us: micros(|stats| stats.p90),
+            latency_p99_us: micros(|stats| stats.p99),
+            latency_max_us: micros(|stats| stats.max),
+        };
+        return report::emit_rows(output_format, "batch_lookup", &summary, "queries", &rows);
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    writeln!(out, "query\tstatus\tmatches\tseeks\tlatency_us\trecords")?;
+    for row in &rows {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{:.1}\t{}",
+            row.query,
+            row.status,
+            row.matches,
+            row.seeks,
+            row.latency_us,
+            row.error.as_deref().unwrap_or(&row.records)
+        )?;
+    }
+    out.flush()?;
+
+    // the summary goes to stderr so stdout stays machine-readable
+    eprintln!(
+        "{} queries in {:.3} seconds ({:.0} queries/s): {} found, {} missed, {} invalid",
+        rows.len(),
+        duration.as_secs_f64(),
+        rows.len() as f64 / duration.as_secs_f64(),
+        found,
+        rows.len() - found - invalid,
+        invalid
+    );
+    if let Some(stats) = stats {
+        stats.print();
+    }
+    Ok(())
+}
+
+fn batch_row(result: &QueryResult) -> BatchRow {
+    let latency_us = result.latency.as_secs_f64() * 1e6;
+    match &result.outcome {
+        Ok((records, seeks)) => BatchRow {
+            query: result.query.clone(),
+            status: if records.is_empty() { "miss" } else { "found" },
+            matches: records.len(),
+            seeks: *seeks,
+            latency_us,
+            records: records
+                .iter()
+                .map(|(nonce, hash)| format!("{}:{}", nonce, hash))
+                .collect::<Vec<String>>()
+                .join(","),
+            error: None,
+        },
+        Err(message) => BatchRow {
+            query: result.query.clone(),
+            status: "error",
+            latency_us,
+            error: Some(message.clone()),
+            ..Default::default()
+        },
+    }
+}
+
+fn parse_query(
+    query: &str,
+    format: PrefixFormat,
+    default_bits: Option<usize>,
+) -> Result<Prefix, String> {
+    let mut fields = query.split_whitespace();
+    let prefix = fields.next().unwrap_or_default();
+    let bits = match fields.next() {
+        Some(bits) => Some(
+            bits.parse::<usize>()
+                .map_err(|_| format!("'{}' is not a valid number of prefix bits", bits))?,
+        ),
+        None => default_bits,
+    };
+    if fields.next().is_some() {
+        return Err("expected '<prefix>' or '<prefix> <bits>'".to_string());
+    }
+    Prefix::parse(prefix, format, bits).map_err(|e| e.to_string())
+}
@@ -0,0 +1,212 @@
+// this file times many random prefix lookups against one vault, cold or warm, and reports latency percentiles
+use crate::batch_lookup::LatencyStats;
+use crate::lookup::{self, SearchStrategy};
+use crate::prefix::Prefix;
+use crate::report::{self, OutputFormat};
+use crate::HASH_SIZE;
+use rand::RngCore;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const DROP_CACHES: &str = "/proc/sys/vm/drop_caches";
+
+// how the page cache is emptied before each cold query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheDrop {
+    None,    // warm run
+    System,  // every clean page on the machine, needs root and only on request
+    Fadvise, // only the pages of the vault file, allowed for anyone who can read it
+}
+
+impl CacheDrop {
+    fn name(&self) -> &'static str {
+        match self {
+            CacheDrop::None => "none",
+            CacheDrop::System => "drop_caches",
+            CacheDrop::Fadvise => "fadvise",
+        }
+    }
+
+    fn apply(&self, file: &File) -> io::Result<()> {
+        match self {
+            CacheDrop::None => Ok(()),
+            CacheDrop::System => fs::write(DROP_CACHES, "1"),
+            CacheDrop::Fadvise => {
+                let result = unsafe {
+                    libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+                };
+                if result == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::from_raw_os_error(result))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BenchSummary {
+    vault: String,
+    num_queries: usize,
+    prefix_bits: usize,
+    strategy: &'static str,
+    cold: bool,
+    cache_drop: &'static str,
+    hits: usize,
+    hit_rate: f64,
+    seeks_mean: f64,
+    seeks_max: usize,
+    duration_s: f64,
+    latency_min_us: f64,
+    latency_mean_us: f64,
+    latency_p50_us: f64,
+    latency_p90_us: f64,
+    latency_p99_us: f64,
+    latency_max_us: f64,
+}
+
+#[derive(Serialize, Default)]
+struct SeekRow {
+    seeks: usize,
+    queries: usize,
+    share: f64,
+}
+
+// issues `num_queries` random prefixes of `prefix_bits` bits (default: k, so about one record is expected per query)
+// one after another, so each latency is that of a single lookup on an otherwise idle vault
+pub fn bench_lookups(
+    path: &Path,
+    num_queries: usize,
+    prefix_bits: Option<usize>,
+    cold: bool,
+    drop_system_cache: bool,
+    strategy: SearchStrategy,
+    format: OutputFormat,
+) -> io::Result<()> {
+    if num_queries == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the benchmark needs at least one query",
+        ));
+    }
+    let (file, footer) = lookup::open_sorted_vault(path)?;
+    let prefix_bits = prefix_bits
+        .unwrap_or(footer.k as usize)
+        .clamp(1, HASH_SIZE * 8);
+    // a cold run evicts only the vault's pages unless the whole machine's cache was asked for
+    let cache_drop = match (cold, drop_system_cache) {
+        (false, _) => CacheDrop::None,
+        (true, false) => CacheDrop::Fadvise,
+        (true, true) => {
+            eprintln!(
+                "Warning: emptying the page cache of the whole machine before every lookup, \
+                 which slows down everything else running on it"
+            );
+            CacheDrop::System
+        }
+    };
+    // fails here, before any query, if the cache can't be dropped
+    cache_drop.apply(&file)?;
+
+    let mut rng = rand::thread_rng();
+    let mut latencies = Vec::with_capacity(num_queries);
+    let mut seek_counts = BTreeMap::new();
+    let mut hits = 0;
+    let mut total_seeks = 0;
+    let start_bench = Instant::now();
+    for _ in 0..num_queries {
+        let mut bytes = [0u8; HASH_SIZE];
+        rng.fill_bytes(&mut bytes);
+        let prefix = Prefix::from_bytes(&bytes, prefix_bits);
+        cache_drop.apply(&file)?;
+
+        let start_query = Instant::now();
+        let (records, seeks) = lookup::find_by_prefix(&file, &footer, &prefix, strategy)?;
+        latencies.push(start_query.elapsed());
+
+        if !records.is_empty() {
+            hits += 1;
+        }
+        total_seeks += seeks;
+        *seek_counts.entry(seeks).or_insert(0usize) += 1;
+    }
+    let duration = start_bench.elapsed();
+    let stats = LatencyStats::from_latencies(latencies).expect("at least one query was timed");
+
+    let hit_rate = hits as f64 / num_queries as f64;
+    let seeks_mean = total_seeks as f64 / num_queries as f64;
+    let seeks_max = seek_counts.keys().last().copied().unwrap_or_default();
+    let rows = seek_counts
+        .iter()
+        .map(|(&seeks, &queries)| SeekRow {
+            seeks,
+            queries,
+            share: queries as f64 / num_queries as f64,
+        })
+        .collect::<Vec<SeekRow>>();
+
+    if !format.is_table() {
+        let micros = |latency: Duration| latency.as_secs_f64() * 1e6;
+        let summary = BenchSummary {
+            vault: path.display().to_string(),
+            num_queries,
+            prefix_bits,
+            strategy: strategy.name(),
+            cold,
+            cache_drop: cache_drop.name(),
+            hits,
+            hit_rate,
+            seeks_mean,
+            seeks_max,
+            duration_s: duration.as_secs_f64(),
+            latency_min_us: micros(stats.min),
+            latency_mean_us: micros(stats.mean),
+            latency_p50_us: micros(stats.p50),
+            latency_p90_us: micros(stats.p90),
+            latency_p99_us: micros(stats.p99),
+            latency_max_us: micros(stats.max),
+        };
+        return report::emit_rows(format, "lookup_bench", &summary, "seek_counts", &rows);
+    }
+
+    println!(
+        "{} {} lookups of random {}-bit prefixes with {} search in {:.3} seconds (cache drop: {})",
+        num_queries,
+        if cold { "cold" } else { "warm" },
+        prefix_bits,
+        strategy.name(),
+        duration.as_secs_f64(),
+        cache_drop.name()
+    );
+    println!(
+        "Hit rate: {:.2}% ({} of {})",
+        hit_rate * 100.0,
+        hits,
+        num_queries
+    );
+    println!(
+        "Latency: min {:?}, mean {:?}, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        stats.min, stats.mean, stats.p50, stats.p90, stats.p99, stats.max
+    );
+    println!(
+        "Seeks per lookup: mean {:.2}, max {}",
+        seeks_mean, seeks_max
+    );
+    println!("{:<6} | {:<10} | {:<6}", "Seeks", "Queries", "Share");
+    println!("{}", "-".repeat(28));
+    for row in &rows {
+        println!(
+            "{:<6} | {:<10} | {:>5.1}%",
+            row.seeks,
+            row.queries,
+            row.share * 100.0
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+#!/bin/bash
+
+# compares the full in-RAM bucket sort against presorted runs + k-way merge, with both sort algorithms
+# usage: compare_sort_modes.sh <k> <threads> <memory in MB> <output dir>
+k="$1"
+threads="$2"
+memory="$3"
+output_dir="$4"
+entrance="./../../target/release/entrance"
+csv_file="vault_csv/sort_modes_k${k}_${threads}t.csv"
+
+if [ -z "$k" ] || [ -z "$threads" ] || [ -z "$memory" ] || [ -z "$output_dir" ]; then
+    echo "usage: $0 <k> <threads> <memory in MB> <output dir>" >&2
+    exit 1
+fi
+mkdir -p "$output_dir" || exit 1
+
+# a vault that fits in memory is generated and sorted in RAM, so neither sort mode would run; the vault (2^k records
+# of 32 bytes) has to be larger than the memory limit
+plan=$("$entrance" plot -k "$k" -t "$threads" -m "$memory" --dry-run --output-format json) || exit 1
+if echo "$plan" | grep -q '"in_memory": true'; then
+    echo "a k=$k vault ($(((1 << k) * 32)) bytes) fits in $memory MB, so the sort modes would never run; use a smaller memory limit" >&2
+    exit 1
+fi
+
+echo "mode,algorithm,hash_time,sort_time,sync_time" >"$csv_file"
+
+for n in {1..5}; do
+    for mode in full merge; do
+    for algorithm in comparison radix; do
+        output_file="${output_dir}/sort_mode_${mode}_${algorithm}.bin"
+        rm -f "$output_file"
+
+        free >/dev/null && sync >/dev/null && sudo sh -c 'echo 3 > /proc/sys/vm/drop_caches' && free >/dev/null
+        sudo sync
+        sleep 1
+
+        output=$("$entrance" plot -k "$k" -t "$threads" -m "$memory" --sort-mode "$mode" --sort-algorithm "$algorithm" -o "$output_file")
+        echo "$mode,$algorithm,$output" >>"$csv_file"
+    done
+    done
+done
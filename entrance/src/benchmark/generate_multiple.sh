@@ -21,7 +21,7 @@ for k in {25..30}; do
             sleep 1
 
             # Capture the output of the program
-            ./../../target/release/entrance -k $k -t $threads -m $memory >>"vault_csv/vault_$k"_"$threads"t".csv"
+            ./../../target/release/entrance plot -k $k -t $threads -m $memory --temp-dir "$output_dir" >>"vault_csv/vault_$k"_"$threads"t".csv"
         done
     done
 done
@@ -36,5 +36,5 @@ for n in {1..5}; do
 
     # Capture the output of the program
 
-    ./../../target/release/entrance -k $k -t $threads -m $memory >>"vault_csv/vault76_eightsocket_$k"_"$threads"t".csv"
+    ./../../target/release/entrance plot -k $k -t $threads -m $memory --temp-dir "$output_dir" >>"vault_csv/vault76_eightsocket_$k"_"$threads"t".csv"
 done
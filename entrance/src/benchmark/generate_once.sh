@@ -19,7 +19,7 @@ sudo sync
 # sar -r 1 >../../stats/memory/memory-stats_$k$threads.txt &
 sleep 5
 
-./../../target/release/entrance -k $k -t $threads -s true -m $memory -d
+./../../target/release/entrance plot -k $k -t $threads -m $memory --temp-dir "$output_dir" -d
 # 17179869184
 # 2147483648
 # dd if=/dev/urandom of=newfile bs=1M count=1024
@@ -10,7 +10,7 @@ rm -rf "${output_dir:?}"/*
 echo "LookupTime(ms),IsExist" > "$csv_file"
 
 # Generate file
-./../../target/release/entrance -k "$k" -t 16
+./../../target/release/entrance plot -k "$k" -t 16 -o "${output_dir}/output.bin"
 
 # Run lookup for 1000 random prefixes
 for i in {1..1000}; do
@@ -21,7 +21,7 @@ for i in {1..1000}; do
     hash=$(python3 get_hash.py "$i" "$hash_len")
 
     # Capture the output of the lookup
-    output=$(./../../target/release/entrance -l "$hash")
+    output=$(./../../target/release/entrance lookup --vault "${output_dir}/output.bin" "$hash")
 
     # Determine if records were found
     if [[ "$output" == *"No records found"* ]]; then
@@ -26,7 +26,7 @@ for ((t = num_threads; t <= max_num_threads; t *= 2)); do
         echo "$k $t $m"
 
         # Run the cargo command with the current value of k and pipe the output to a file
-        output=$(./../../target/release/entrance -k "$k" -t "$t" -m "$m")
+        output=$(./../../target/release/entrance plot -k "$k" -t "$t" -m "$m" -o "$output_file")
 
         echo "$t,$m,$output" >>"$csv_file"
     done
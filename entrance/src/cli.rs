@@ -0,0 +1,933 @@
+// this file defines the command line: one subcommand per operation, each with its own validated arguments,
+// parsed into plain options so that main only has to dispatch them
+use crate::audit::AuditMode;
+use crate::closest::{Distance, MAX_NEIGHBOURS};
+use crate::farm::FarmSource;
+use crate::hash_sorter::SortAlgorithm;
+use crate::lookup::SearchStrategy;
+use crate::plot::PlotOptions;
+use crate::plot_plan::MAX_K;
+use crate::prefix::{Prefix, PrefixFormat};
+use crate::proof::ProofFormat;
+use crate::report::OutputFormat;
+use crate::serve::{Endpoint, ServeLimits};
+use crate::simulate::SimulationConfig;
+use clap::{App, Arg, ArgGroup, ArgMatches, ErrorKind};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub struct Cli {
+    pub threads: usize,
+    pub debug: bool,
+    pub output_format: OutputFormat,
+    pub command: Command,
+}
+
+// lookups run against a single vault or against every plot of a farm
+pub enum Target {
+    Vault(PathBuf),
+    Farm(PathBuf),
+}
+
+pub enum LookupQuery {
+    Prefix(Prefix),
+    Batch {
+        source: String, // one prefix per line, '-' for stdin
+        prefix_format: PrefixFormat,
+        prefix_bits: Option<usize>,
+    },
+    Nonce(u64),
+}
+
+pub enum Command {
+    Plot {
+        options: PlotOptions,
+        dry_run: bool,      // print the bucket plan, write nothing
+        print: Option<u64>, // records to print from every new vault
+        verify: bool,
+        audit: Option<AuditMode>,
+    },
+    Lookup {
+        target: Target,
+        query: LookupQuery,
+        strategy: SearchStrategy,
+    },
+    Closest {
+        target: Target,
+        challenge: Prefix,
+        distance: Distance,
+        top: usize,
+        neighbours: u64,
+    },
+    Quality {
+        farm: PathBuf,
+        challenge: Prefix,
+        difficulty: f64,
+    },
+    Bench {
+        vault: PathBuf,
+        num_queries: usize,
+        prefix_bits: Option<usize>,
+        cold: bool,
+        drop_system_cache: bool,
+        strategy: SearchStrategy,
+    },
+    Print {
+        vault: PathBuf,
+        count: u64,
+    },
+    Verify {
+        vault: PathBuf,
+        memory_limit: usize, // in MB, for the duplicate nonce check
+    },
+    Audit {
+        vault: PathBuf,
+        mode: AuditMode,
+    },
+    Info {
+        vault: PathBuf,
+    },
+    Serve {
+        farm: FarmSource,
+        endpoint: Endpoint,
+        limits: ServeLimits,
+    },
+    Client {
+        endpoint: Endpoint,
+        requests: Option<Vec<String>>, // read from stdin when none are given
+    },
+    Prove {
+        vault: PathBuf,
+        challenge: Prefix,
+        format: ProofFormat,
+        out: Option<PathBuf>,
+    },
+    VerifyProof {
+        source: String, // '-' for stdin
+    },
+    Simulate {
+        farm: FarmSource,
+        config: SimulationConfig,
+    },
+}
+
+pub fn app() -> App<'static> {
+    App::new("Vault-76")
+        .version("3.0")
+        .about("Cryptographic hash tool that generates hashes for unique nonces using BLAKE3 hashing function. This vault also has the ability to store each record (nonce/hash pair) into a DashMap, by using a specified prefix as the key, and the record as a value. You can also look up records efficiently.")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::with_name("threads")
+                .short('t')
+                .long("threads")
+                .takes_value(true)
+                .default_value("1")
+                .validator(positive)
+                .global(true)
+                .help("Number of threads to use"),
+        )
+        .arg(
+            Arg::with_name("output_format")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(["table", "json", "jsonl", "csv"])
+                .default_value("table")
+                .global(true)
+                .help("Format of lookup results, printed records, verify results and the run report"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short('d')
+                .long("debug")
+                .global(true)
+                .help("Prints debug information to the command line"),
+        )
+        .subcommand(
+            App::new("plot")
+                .about("Generate, sort and write one or more vaults")
+                .arg(
+                    Arg::with_name("k-value")
+                        .short('k')
+                        .long("k-value")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(k_value)
+                        .help("Specify k value to compute 2^k nonces"),
+                )
+                .arg(memory_limit_arg(
+                    "Memory, in MB, that generation and sorting may use",
+                ))
+                .arg(
+                    Arg::with_name("file_size")
+                        .short('f')
+                        .long("file-size")
+                        .takes_value(true)
+                        .validator(positive)
+                        .help("Bytes of records to generate, 32 per record (default: 2^k records)"),
+                )
+                .arg(
+                    Arg::with_name("no_sort")
+                        .long("no-sort")
+                        .help("Write the records in generation order; lookups need a sorted vault"),
+                )
+                .arg(
+                    Arg::with_name("sort_mode")
+                        .long("sort-mode")
+                        .takes_value(true)
+                        .possible_values(["full", "merge"])
+                        .default_value("full")
+                        .help("full: sort each disk bucket in RAM after generation; merge: sort memory buckets before flushing, then k-way merge the sorted runs"),
+                )
+                .arg(
+                    Arg::with_name("sort_algorithm")
+                        .long("sort-algorithm")
+                        .takes_value(true)
+                        .possible_values(["comparison", "radix"])
+                        .default_value("comparison")
+                        .help("Algorithm used to sort records inside a bucket"),
+                )
+                .arg(
+                    Arg::with_name("plots")
+                        .short('n')
+                        .long("plots")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of vaults to create back to back"),
+                )
+                .arg(
+                    Arg::with_name("temp_dir")
+                        .long("temp-dir")
+                        .takes_value(true)
+                        .required_unless_present_any(["output", "dry_run"])
+                        .help("Fast directory (e.g. NVMe) where hashes are generated and sorted; required unless --output names the vault"),
+                )
+                .arg(
+                    Arg::with_name("final_dir")
+                        .long("final-dir")
+                        .takes_value(true)
+                        .help("Slow directory (e.g. HDD) the finished vault is streamed to"),
+                )
+                .arg(
+                    Arg::with_name("background_copy")
+                        .long("background-copy")
+                        .requires("final_dir")
+                        .help("Start the next plot while the previous one is still being copied to --final-dir"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .conflicts_with_all(&["temp_dir", "final_dir"])
+                        .help("Exact path of the vault to create (default: a unique name inside --temp-dir)"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .conflicts_with_all(&["print", "verify", "audit"])
+                        .help("Print the bucket plan for these settings and exit without writing anything"),
+                )
+                .arg(
+                    Arg::with_name("print")
+                        .short('p')
+                        .long("print")
+                        .takes_value(true)
+                        .help("Number of records to print from every new vault"),
+                )
+                .arg(
+                    Arg::with_name("verify")
+                        .short('v')
+                        .long("verify")
+                        .help("Verify that every new vault is in sorted order"),
+                )
+                .arg(
+                    Arg::with_name("audit")
+                        .long("audit")
+                        .takes_value(true)
+                        .possible_values(["full", "sample"])
+                        .help("Recompute BLAKE3 from the stored nonces of every new vault: for every record, or for a random sample"),
+                )
+                .args(sample_args()),
+        )
+        .subcommand(
+            App::new("lookup")
+                .about("Look up records by hash prefix (one, or a batch of them) or by nonce")
+                .args(target_args())
+                .arg(
+                    Arg::with_name("prefix")
+                        .takes_value(true)
+                        .help("Prefix the hashes must start with"),
+                )
+                .arg(
+                    Arg::with_name("batch")
+                        .long("batch")
+                        .takes_value(true)
+                        .conflicts_with("farm")
+                        .help("Look up every prefix in a file (one per line, '-' for stdin) and print one tab-separated result line each"),
+                )
+                .arg(
+                    Arg::with_name("nonce")
+                        .long("nonce")
+                        .takes_value(true)
+                        .conflicts_with("farm")
+                        .help("Find the record of a nonce and check that its stored hash is correct"),
+                )
+                .group(
+                    ArgGroup::new("query")
+                        .args(&["prefix", "batch", "nonce"])
+                        .required(true),
+                )
+                .args(prefix_args())
+                .arg(search_strategy_arg()),
+        )
+        .subcommand(
+            App::new("closest")
+                .about("Find the stored hashes nearest to a challenge")
+                .args(target_args())
+                .arg(
+                    Arg::with_name("challenge")
+                        .long("challenge")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Challenge, written like a lookup prefix; missing bits are zero"),
+                )
+                .arg(
+                    Arg::with_name("distance")
+                        .long("distance")
+                        .takes_value(true)
+                        .possible_values(["numeric", "xor"])
+                        .default_value("numeric")
+                        .help("How the distance between a hash and the challenge is measured"),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(positive)
+                        .help("Number of nearest records to return"),
+                )
+                .arg(
+                    Arg::with_name("neighbours")
+                        .long("neighbours")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(neighbours)
+                        .help("How many buckets next to the challenge's bucket may be searched"),
+                )
+                .args(prefix_args()),
+        )
+        .subcommand(
+            App::new("quality")
+                .about("Score every plot of a farm against a challenge: its best hash, and whether it reaches a difficulty")
+                .arg(
+                    Arg::with_name("farm")
+                        .long("farm")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Directory of vaults, or a manifest file listing one vault per line"),
+                )
+                .arg(
+                    Arg::with_name("challenge")
+                        .long("challenge")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Challenge, written like a lookup prefix; missing bits are zero"),
+                )
+                .arg(
+                    Arg::with_name("difficulty")
+                        .long("difficulty")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Quality, in leading bits shared with the challenge, that a proof needs to win"),
+                )
+                .args(prefix_args()),
+        )
+        .subcommand(
+            App::new("bench")
+                .about("Time lookups of random prefixes and report latency percentiles, seeks and hit rate")
+                .arg(vault_arg("Vault to benchmark"))
+                .arg(
+                    Arg::with_name("queries")
+                        .long("queries")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .validator(positive)
+                        .help("Number of lookups to time"),
+                )
+                .arg(
+                    Arg::with_name("prefix_bits")
+                        .long("prefix-bits")
+                        .takes_value(true)
+                        .help("Bits of every random prefix (default: k, so about one record matches)"),
+                )
+                .arg(
+                    Arg::with_name("cold")
+                        .long("cold")
+                        .help("Evict the vault's pages from the page cache before every lookup"),
+                )
+                .arg(
+                    Arg::with_name("drop_system_cache")
+                        .long("drop-system-cache")
+                        .requires("cold")
+                        .help("With --cold, empty the page cache of the whole machine instead (needs root)"),
+                )
+                .arg(search_strategy_arg()),
+        )
+        .subcommand(
+            App::new("print")
+                .about("Print the first records of a vault")
+                .arg(vault_arg("Vault to print"))
+                .arg(
+                    Arg::with_name("count")
+                        .short('n')
+                        .long("count")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Number of records to print"),
+                ),
+        )
+        .subcommand(
+            App::new("verify")
+                .about("Check that a vault is sorted, every record sits in its bucket and nothing is duplicated")
+                .arg(vault_arg("Vault to verify"))
+                .arg(memory_limit_arg(
+                    "Memory, in MB, for the duplicate nonce check; beyond it, nonces are spilled to a scratch file next to the vault",
+                )),
+        )
+        .subcommand(
+            App::new("audit")
+                .about("Recompute BLAKE3 from the stored nonces and compare it to the stored hashes")
+                .arg(vault_arg("Vault to audit"))
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .takes_value(true)
+                        .possible_values(["full", "sample"])
+                        .default_value("full")
+                        .help("Recompute every record, or a random sample of them"),
+                )
+                .args(sample_args()),
+        )
+        .subcommand(
+            App::new("info")
+                .about("Show the parameters a vault was created with")
+                .arg(vault_arg("Vault to describe")),
+        )
+        .subcommand(
+            App::new("serve")
+                .about("Keep vaults open and answer PREFIX, NONCE and CLOSEST requests over a Unix socket or TCP")
+                .args(farm_source_args(
+                    "Vault to serve (repeat for several)",
+                    "Directory of vaults, or a manifest file listing one vault per line, to serve",
+                ))
+                .args(endpoint_args(
+                    "Unix domain socket to listen on",
+                    "TCP address to listen on, e.g. 127.0.0.1:7676",
+                ))
+                .group(endpoint_group())
+                .arg(
+                    Arg::with_name("max_results")
+                        .long("max-results")
+                        .takes_value(true)
+                        .default_value("10000")
+                        .validator(positive)
+                        .help("Most records one PREFIX or CLOSEST answer may hold; larger requests get an error"),
+                )
+                .arg(
+                    Arg::with_name("max_connections")
+                        .long("max-connections")
+                        .takes_value(true)
+                        .default_value("64")
+                        .validator(positive)
+                        .help("Most connections answered at once; further clients get an error and are closed"),
+                ),
+        )
+        .subcommand(
+            App::new("client")
+                .about("Send requests to a running vault server and print its responses")
+                .args(endpoint_args(
+                    "Unix domain socket of the server",
+                    "TCP address of the server",
+                ))
+                .group(endpoint_group())
+                .arg(
+                    Arg::with_name("requests")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .help("Requests to send, e.g. \"PREFIX 00ab\" (default: one per line from stdin)"),
+                ),
+        )
+        .subcommand(
+            App::new("prove")
+                .about("Answer a challenge with a proof taken from a vault")
+                .arg(vault_arg("Vault to look the challenge up in"))
+                .arg(
+                    Arg::with_name("challenge")
+                        .long("challenge")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Challenge in hex; the proof's hash must start with it"),
+                )
+                .arg(
+                    Arg::with_name("challenge_bits")
+                        .long("challenge-bits")
+                        .takes_value(true)
+                        .help("Match only this many leading bits of the challenge (default: every hex digit given)"),
+                )
+                .arg(
+                    Arg::with_name("proof_format")
+                        .long("proof-format")
+                        .takes_value(true)
+                        .possible_values(["json", "binary"])
+                        .default_value("json")
+                        .help("Encoding of the proof"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .takes_value(true)
+                        .help("File to write the proof to (default: stdout)"),
+                ),
+        )
+        .subcommand(
+            App::new("verify-proof")
+                .about("Check a proof using nothing but the proof itself, by recomputing BLAKE3")
+                .arg(
+                    Arg::with_name("proof")
+                        .takes_value(true)
+                        .default_value("-")
+                        .help("Proof file, binary or JSON ('-' for stdin)"),
+                ),
+        )
+        .subcommand(
+            App::new("simulate")
+                .about("Run a local mock chain that challenges a farm every block and scores its answers")
+                .args(farm_source_args(
+                    "Vault to farm (repeat for several)",
+                    "Directory of vaults, or a manifest file listing one vault per line, to farm",
+                ))
+                .arg(
+                    Arg::with_name("blocks")
+                        .long("blocks")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(positive)
+                        .help("Number of blocks to simulate"),
+                )
+                .arg(
+                    Arg::with_name("block_interval")
+                        .long("block-interval")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("Milliseconds between two challenges (0 issues them back to back)"),
+                )
+                .arg(
+                    Arg::with_name("deadline")
+                        .long("deadline")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("Milliseconds a plot has to answer a challenge"),
+                )
+                .arg(
+                    Arg::with_name("difficulty")
+                        .long("difficulty")
+                        .takes_value(true)
+                        .help("Quality bits a proof needs to win (default: log2 of the farm's records, rounded up)"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .default_value("vault76")
+                        .help("Genesis seed of the chain; the same seed and vaults give the same run"),
+                ),
+        )
+}
+
+// parses the command line of this process, exiting with clap's usage message on anything invalid
+pub fn parse() -> Cli {
+    let matches = app().get_matches();
+    let (name, matches) = matches.subcommand().expect("a subcommand is required");
+
+    // global arguments are visible from every subcommand, wherever they were written
+    let threads = matches.value_of_t_or_exit("threads");
+    let debug = matches.is_present("debug");
+    let output_format = OutputFormat::from_name(matches.value_of("output_format").unwrap())
+        .expect("Error parsing output format");
+
+    let command = match name {
+        "plot" => parse_plot(matches, threads, debug),
+        "lookup" => parse_lookup(matches),
+        "closest" => Command::Closest {
+            target: target(matches),
+            challenge: prefix(matches, "challenge"),
+            distance: Distance::from_name(matches.value_of("distance").unwrap())
+                .expect("Error parsing distance"),
+            top: matches.value_of_t_or_exit("top"),
+            neighbours: matches.value_of_t_or_exit("neighbours"),
+        },
+        "quality" => Command::Quality {
+            farm: path(matches, "farm"),
+            challenge: prefix(matches, "challenge"),
+            difficulty: matches.value_of_t_or_exit("difficulty"),
+        },
+        "bench" => Command::Bench {
+            vault: path(matches, "vault"),
+            num_queries: matches.value_of_t_or_exit("queries"),
+            prefix_bits: optional(matches, "prefix_bits"),
+            cold: matches.is_present("cold"),
+            drop_system_cache: matches.is_present("drop_system_cache"),
+            strategy: search_strategy(matches),
+        },
+        "print" => Command::Print {
+            vault: path(matches, "vault"),
+            count: matches.value_of_t_or_exit("count"),
+        },
+        "verify" => Command::Verify {
+            vault: path(matches, "vault"),
+            memory_limit: matches.value_of_t_or_exit("memory_limit"),
+        },
+        "audit" => Command::Audit {
+            vault: path(matches, "vault"),
+            mode: audit_mode(matches, matches.value_of("mode").unwrap()),
+        },
+        "info" => Command::Info {
+            vault: path(matches, "vault"),
+        },
+        "serve" => Command::Serve {
+            farm: farm_source(matches),
+            endpoint: endpoint(matches),
+            limits: ServeLimits {
+                max_results: matches.value_of_t_or_exit("max_results"),
+                max_connections: matches.value_of_t_or_exit("max_connections"),
+            },
+        },
+        "client" => Command::Client {
+            endpoint: endpoint(matches),
+            requests: matches
+                .values_of("requests")
+                .map(|requests| requests.map(String::from).collect()),
+        },
+        "prove" => {
+            let challenge_bits = optional(matches, "challenge_bits");
+            Command::Prove {
+                vault: path(matches, "vault"),
+                challenge: Prefix::parse(
+                    matches.value_of("challenge").unwrap(),
+                    PrefixFormat::Hex,
+                    challenge_bits,
+                )
+                .unwrap_or_else(|e| invalid_value("--challenge", e)),
+                format: ProofFormat::from_name(matches.value_of("proof_format").unwrap())
+                    .expect("Error parsing proof format"),
+                out: matches.value_of("out").map(PathBuf::from),
+            }
+        }
+        "verify-proof" => Command::VerifyProof {
+            source: matches.value_of("proof").unwrap().to_string(),
+        },
+        "simulate" => Command::Simulate {
+            farm: farm_source(matches),
+            config: SimulationConfig {
+                blocks: matches.value_of_t_or_exit("blocks"),
+                block_interval: Duration::from_millis(matches.value_of_t_or_exit("block_interval")),
+                deadline: Duration::from_millis(matches.value_of_t_or_exit("deadline")),
+                difficulty: optional(matches, "difficulty"),
+                seed: matches.value_of("seed").unwrap().to_string(),
+            },
+        },
+        _ => unreachable!("every subcommand is handled"),
+    };
+
+    Cli {
+        threads,
+        debug,
+        output_format,
+        command,
+    }
+}
+
+fn parse_plot(matches: &ArgMatches, num_threads: usize, debug: bool) -> Command {
+    let options = PlotOptions {
+        k: matches.value_of_t_or_exit("k-value"),
+        memory_limit: matches.value_of_t_or_exit("memory_limit"),
+        file_size: optional(matches, "file_size"),
+        num_threads,
+        sorting: !matches.is_present("no_sort"),
+        merge_sort: matches.value_of("sort_mode") == Some("merge"),
+        sort_algorithm: match matches.value_of("sort_algorithm") {
+            Some("radix") => SortAlgorithm::Radix,
+            _ => SortAlgorithm::Comparison,
+        },
+        num_plots: matches.value_of_t_or_exit("plots"),
+        // without --temp-dir, the vault named by --output is generated where it ends up
+        temp_dir: match matches.value_of("temp_dir") {
+            Some(temp_dir) => PathBuf::from(temp_dir),
+            None => matches
+                .value_of("output")
+                .and_then(|output| Path::new(output).parent())
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        },
+        final_dir: matches.value_of("final_dir").map(PathBuf::from),
+        background_copy: matches.is_present("background_copy"),
+        output: matches.value_of("output").map(PathBuf::from),
+        debug,
+    };
+    Command::Plot {
+        options,
+        dry_run: matches.is_present("dry_run"),
+        print: optional(matches, "print"),
+        verify: matches.is_present("verify"),
+        audit: matches
+            .value_of("audit")
+            .map(|mode| audit_mode(matches, mode)),
+    }
+}
+
+fn parse_lookup(matches: &ArgMatches) -> Command {
+    let query = if let Some(source) = matches.value_of("batch") {
+        LookupQuery::Batch {
+            source: source.to_string(),
+            prefix_format: prefix_format(matches),
+            prefix_bits: optional(matches, "prefix_bits"),
+        }
+    } else if matches.is_present("nonce") {
+        LookupQuery::Nonce(matches.value_of_t_or_exit("nonce"))
+    } else {
+        LookupQuery::Prefix(prefix(matches, "prefix"))
+    };
+    Command::Lookup {
+        target: target(matches),
+        query,
+        strategy: search_strategy(matches),
+    }
+}
+
+fn memory_limit_arg(help: &'static str) -> Arg<'static> {
+    Arg::with_name("memory_limit")
+        .short('m')
+        .long("memory-limit")
+        .takes_value(true)
+        .default_value("2048")
+        .validator(positive)
+        .help(help)
+}
+
+fn vault_arg(help: &'static str) -> Arg<'static> {
+    Arg::with_name("vault")
+        .long("vault")
+        .takes_value(true)
+        .required(true)
+        .help(help)
+}
+
+// --vault or --farm, for commands that run against either
+fn target_args() -> [Arg<'static>; 2] {
+    [
+        Arg::with_name("vault")
+            .long("vault")
+            .takes_value(true)
+            .required_unless_present("farm")
+            .help("Vault to search"),
+        Arg::with_name("farm")
+            .long("farm")
+            .takes_value(true)
+            .conflicts_with("vault")
+            .help("Directory of vaults, or a manifest file listing one vault per line, to search every plot of"),
+    ]
+}
+
+// repeated --vault or one --farm, for commands that keep a whole farm open
+fn farm_source_args(vault_help: &'static str, farm_help: &'static str) -> [Arg<'static>; 2] {
+    [
+        Arg::with_name("vault")
+            .long("vault")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .required_unless_present("farm")
+            .help(vault_help),
+        Arg::with_name("farm")
+            .long("farm")
+            .takes_value(true)
+            .conflicts_with("vault")
+            .help(farm_help),
+    ]
+}
+
+fn endpoint_args(socket_help: &'static str, tcp_help: &'static str) -> [Arg<'static>; 2] {
+    [
+        Arg::with_name("socket")
+            .long("socket")
+            .takes_value(true)
+            .help(socket_help),
+        Arg::with_name("tcp")
+            .long("tcp")
+            .takes_value(true)
+            .help(tcp_help),
+    ]
+}
+
+// exactly one of --socket and --tcp
+fn endpoint_group() -> ArgGroup<'static> {
+    ArgGroup::new("endpoint")
+        .args(&["socket", "tcp"])
+        .required(true)
+}
+
+fn prefix_args() -> [Arg<'static>; 2] {
+    [
+        Arg::with_name("prefix_format")
+            .long("prefix-format")
+            .takes_value(true)
+            .possible_values(["hex", "bin", "base64"])
+            .default_value("hex")
+            .help("How prefixes and challenges are written"),
+        Arg::with_name("prefix_bits")
+            .long("prefix-bits")
+            .takes_value(true)
+            .help("Only match the first N bits of the prefix (default: every bit it spells out)"),
+    ]
+}
+
+fn search_strategy_arg() -> Arg<'static> {
+    Arg::with_name("search_strategy")
+        .long("search-strategy")
+        .takes_value(true)
+        .possible_values(["binary", "interpolation"])
+        .default_value("binary")
+        .help("How prefix lookups find the first match in a bucket; the seek count of each lookup is printed")
+}
+
+fn sample_args() -> [Arg<'static>; 2] {
+    [
+        Arg::with_name("sample_size")
+            .long("sample-size")
+            .takes_value(true)
+            .default_value("1000")
+            .help("Number of random records to recompute in a sampled audit"),
+        Arg::with_name("confidence")
+            .long("confidence")
+            .takes_value(true)
+            .default_value("0.99")
+            .validator(confidence)
+            .help("Confidence level (between 0 and 1) for the corruption bound of a sampled audit"),
+    ]
+}
+
+fn k_value(value: &str) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(k) if (1..=MAX_K).contains(&k) => Ok(()),
+        _ => Err(format!("k must be a whole number between 1 and {}", MAX_K)),
+    }
+}
+
+fn positive(value: &str) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(()),
+        _ => Err("must be a whole number above zero".to_string()),
+    }
+}
+
+fn neighbours(value: &str) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(neighbours) if neighbours <= MAX_NEIGHBOURS => Ok(()),
+        _ => Err(format!(
+            "must be a whole number of buckets between 0 and {}",
+            MAX_NEIGHBOURS
+        )),
+    }
+}
+
+fn confidence(value: &str) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(confidence) if confidence > 0.0 && confidence < 1.0 => Ok(()),
+        _ => Err("must be a number between 0 and 1".to_string()),
+    }
+}
+
+// reports a value only checkable once the other arguments are known, the way clap reports its own errors
+fn invalid_value(arg: &str, error: impl Display) -> ! {
+    clap::Error::raw(
+        ErrorKind::InvalidValue,
+        format!("Invalid value for '{}': {}\n", arg, error),
+    )
+    .exit()
+}
+
+// an argument without a default, parsed when it was given
+fn optional<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    matches
+        .is_present(name)
+        .then(|| matches.value_of_t_or_exit(name))
+}
+
+fn path(matches: &ArgMatches, name: &str) -> PathBuf {
+    PathBuf::from(matches.value_of(name).unwrap())
+}
+
+fn target(matches: &ArgMatches) -> Target {
+    match matches.value_of("farm") {
+        Some(farm) => Target::Farm(PathBuf::from(farm)),
+        None => Target::Vault(path(matches, "vault")),
+    }
+}
+
+fn farm_source(matches: &ArgMatches) -> FarmSource {
+    match matches.value_of("farm") {
+        Some(farm) => FarmSource::Path(PathBuf::from(farm)),
+        None => FarmSource::Vaults(
+            matches
+                .values_of("vault")
+                .unwrap()
+                .map(PathBuf::from)
+                .collect(),
+        ),
+    }
+}
+
+fn endpoint(matches: &ArgMatches) -> Endpoint {
+    match matches.value_of("socket") {
+        Some(socket) => Endpoint::Unix(PathBuf::from(socket)),
+        None => Endpoint::Tcp(matches.value_of("tcp").unwrap().to_string()),
+    }
+}
+
+fn prefix_format(matches: &ArgMatches) -> PrefixFormat {
+    PrefixFormat::from_name(matches.value_of("prefix_format").unwrap())
+        .expect("Error parsing prefix format")
+}
+
+// a prefix or challenge argument, read with --prefix-format and --prefix-bits
+fn prefix(matches: &ArgMatches, name: &str) -> Prefix {
+    Prefix::parse(
+        matches.value_of(name).unwrap(),
+        prefix_format(matches),
+        optional(matches, "prefix_bits"),
+    )
+    .unwrap_or_else(|e| invalid_value(name, e))
+}
+
+fn search_strategy(matches: &ArgMatches) -> SearchStrategy {
+    SearchStrategy::from_name(matches.value_of("search_strategy").unwrap())
+        .expect("Error parsing search strategy")
+}
+
+fn audit_mode(matches: &ArgMatches, mode: &str) -> AuditMode {
+    if mode == "full" {
+        AuditMode::Full
+    } else {
+        AuditMode::Sample {
+            sample_size: matches.value_of_t_or_exit("sample_size"),
+            confidence: matches.value_of_t_or_exit("confidence"),
+        }
+    }
+}
@@ -0,0 +1,684 @@
+// this file finds the stored hashes nearest to a challenge, under numeric or XOR distance
+use crate::hash_generator::extract_prefix;
+use crate::lookup::{
+    self, bucket_range, hash_to_string, is_padding, lower_bound, nonce_to_decimal, read_records,
+    BucketIndex,
+};
+use crate::plot_plan::MAX_K;
+use crate::prefix::Prefix;
+use crate::report::{self, OutputFormat};
+use crate::vault::VaultFooter;
+use crate::{HASH_SIZE, NONCE_SIZE, RECORD_SIZE};
+use serde::Serialize;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+const XOR_LEAF_RECORDS: u64 = 64; // below this many records a range is read whole instead of split further
+pub const MAX_NEIGHBOURS: u64 = 1 << MAX_K; // no vault has more buckets than it can have records
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distance {
+    Numeric, // |hash - challenge|, both read as 208-bit big-endian integers
+    Xor,     // hash ^ challenge
+}
+
+impl Distance {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "numeric" => Some(Distance::Numeric),
+            "xor" => Some(Distance::Xor),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Distance::Numeric => "numeric",
+            Distance::Xor => "xor",
+        }
+    }
+
+    fn between(&self, hash: &[u8; HASH_SIZE], challenge: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+        match self {
+            Distance::Numeric => numeric_distance(hash, challenge),
+            Distance::Xor => {
+                let mut distance = [0u8; HASH_SIZE];
+                for (i, byte) in distance.iter_mut().enumerate() {
+                    *byte = hash[i] ^ challenge[i];
+                }
+                distance
+            }
+        }
+    }
+}
+
+pub struct ClosestMatch {
+    pub index: u64, // record index in the vault
+    pub nonce: [u8; NONCE_SIZE],
+    pub hash: [u8; HASH_SIZE],
+    pub distance: [u8; HASH_SIZE],
+}
+
+#[derive(Serialize)]
+struct ClosestSummary {
+    vault: String,
+    challenge_hex: String,
+    challenge_bits: usize,
+    distance_metric: &'static str,
+    top: usize,
+    neighbours: u64,
+    num_matches: usize,
+    buckets_searched: usize,
+    seeks: usize,
+    duration_us: f64,
+}
+
+#[derive(Serialize, Default)]
+struct ClosestRow {
+    rank: usize,
+    record_index: u64,
+    nonce: u64,
+    hash_hex: String,
+    distance_hex: String,
+}
+
+pub struct ClosestResult {
+    pub matches: Vec<ClosestMatch>, // nearest first
+    pub buckets_searched: usize,
+    pub seek_count: usize,
+}
+
+pub fn lookup_closest(
+    path: &Path,
+    challenge: &Prefix,
+    distance: Distance,
+    top: usize,
+    neighbours: u64,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let (file, footer) = lookup::open_sorted_vault(path)?;
+
+    let start_time = Instant::now();
+    let result = find_closest(
+        &file,
+        &footer,
+        challenge.bytes(),
+        distance,
+        top,
+        neighbours,
+        None,
+    )?;
+    let duration = start_time.elapsed();
+
+    if !format.is_table() {
+        let summary = ClosestSummary {
+            vault: path.display().to_string(),
+            challenge_hex: challenge.hex(),
+            challenge_bits: challenge.bits(),
+            distance_metric: distance.name(),
+            top,
+            neighbours,
+            num_matches: result.matches.len(),
+            buckets_searched: result.buckets_searched,
+            seeks: result.seek_count,
+            duration_us: duration.as_secs_f64() * 1e6,
+        };
+        let rows = result
+            .matches
+            .iter()
+            .enumerate()
+            .map(|(rank, found)| ClosestRow {
+                rank: rank + 1,
+                record_index: found.index,
+                nonce: nonce_to_decimal(&found.nonce),
+                hash_hex: hash_to_string(&found.hash),
+                distance_hex: hash_to_string(&found.distance),
+            })
+            .collect::<Vec<ClosestRow>>();
+        return report::emit_rows(format, "closest", &summary, "matches", &rows);
+    }
+
+    if result.matches.is_empty() {
+        println!(
+            "No records in the {} bucket(s) searched for challenge {}",
+            result.buckets_searched, challenge
+        );
+    } else {
+        println!(
+            "{:<4} | {:<12} | {:<16} | {:<52} | {:<52}",
+            "Rank", "Record", "Nonce (Decimal)", "Hash (Hex)", "Distance (Hex)"
+        );
+        println!("{}", "-".repeat(148));
+        for (rank, found) in result.matches.iter().enumerate() {
+            println!(
+                "{:<4} | {:<12} | {:<16} | {} | {}",
+                rank + 1,
+                found.index,
+                nonce_to_decimal(&found.nonce),
+                hash_to_string(&found.hash),
+                hash_to_string(&found.distance)
+            );
+        }
+    }
+
+    println!(
+        "Search duration: {:?}, Seek operations: {}, Buckets searched: {}",
+        duration, result.seek_count, result.buckets_searched
+    );
+    Ok(())
+}
+
+// the `top` records nearest to `challenge`, looking only in its bucket and up to `neighbours` buckets around it
+// (for numeric distance the buckets on either side, for XOR the buckets whose index differs by at most `neighbours`);
+// a prebuilt bucket index saves looking for the padding at the end of each bucket
+pub fn find_closest(
+    file: &File,
+    footer: &VaultFooter,
+    challenge: &[u8; HASH_SIZE],
+    distance: Distance,
+    top: usize,
+    neighbours: u64,
+    index: Option<&BucketIndex>,
+) -> io::Result<ClosestResult> {
+    // `top` comes straight from the user, so it is never used to size an allocation beyond the vault itself
+    let top = top.min(footer.num_records as usize);
+    let mut result = ClosestResult {
+        matches: Vec::new(),
+        buckets_searched: 0,
+        seek_count: 0,
+    };
+    let bucket_size = footer.bucket_size.max(1);
+    let num_buckets = footer.num_records.div_ceil(bucket_size);
+    let prefix_size = footer.prefix_size as usize;
+    let challenge_bucket = extract_prefix(challenge, prefix_size);
+    if top == 0 || challenge_bucket >= num_buckets {
+        return Ok(result);
+    }
+    // past this every bucket is in reach, under either distance, so larger values only cost overflow
+    let neighbours = neighbours.min(num_buckets.next_power_of_two() - 1);
+
+    let searcher = Searcher {
+        file,
+        footer,
+        index,
+        challenge,
+        distance,
+    };
+    match distance {
+        Distance::Numeric => {
+            let first = challenge_bucket.saturating_sub(neighbours);
+            let last = challenge_bucket
+                .saturating_add(neighbours)
+                .min(num_buckets - 1);
+            searcher.numeric(challenge_bucket, first, last, top, &mut result)?;
+        }
+        Distance::Xor => {
+            // every record of bucket b ^ 0 is closer than any of b ^ 1, which are closer than any of b ^ 2, ...
+            for offset in 0..=neighbours {
+                let bucket = challenge_bucket ^ offset;
+                if result.matches.len() >= top {
+                    break;
+                }
+                if bucket >= num_buckets {
+                    continue;
+                }
+                let (start, end) = searcher.real_range(bucket, &mut result)?;
+                let mut path = [0u8; HASH_SIZE];
+                for bit in 0..prefix_size {
+                    if bucket >> (prefix_size - 1 - bit) & 1 == 1 {
+                        set_bit(&mut path, bit);
+                    }
+                }
+                searcher.xor(start, end, prefix_size, path, top, &mut result)?;
+            }
+        }
+    }
+    Ok(result)
+}
+
+struct Searcher<'a> {
+    file: &'a File,
+    footer: &'a VaultFooter,
+    index: Option<&'a BucketIndex>,
+    challenge: &'a [u8; HASH_SIZE],
+    distance: Distance,
+}
+
+impl Searcher<'_> {
+    // the records of a bucket, without the zero padding at its end
+    fn real_range(&self, bucket: u64, result: &mut ClosestResult) -> io::Result<(u64, u64)> {
+        result.buckets_searched += 1;
+        match self.index {
+            Some(index) => Ok(index.range(bucket)),
+            None => bucket_range(self.file, self.footer, bucket, &mut result.seek_count),
+        }
+    }
+
+    // the nearest records sit right before and after where the challenge would be inserted, so walk outwards from there
+    fn numeric(
+        &self,
+        challenge_bucket: u64,
+        first_bucket: u64,
+        last_bucket: u64,
+        top: usize,
+        result: &mut ClosestResult,
+    ) -> io::Result<()> {
+        let (start, end) = self.real_range(challenge_bucket, result)?;
+        let challenge = Prefix::from_bytes(self.challenge, HASH_SIZE * 8);
+        let insert_at = lower_bound(self.file, start, end, &challenge, &mut result.seek_count)?;
+        let mut candidates = Vec::new();
+
+        // upwards, into the following buckets when this one runs out
+        let (mut from, mut to, mut bucket) = (insert_at, end, challenge_bucket);
+        let mut needed = top as u64;
+        loop {
+            let take = needed.min(to - from);
+            self.read_candidates(from, take, &mut candidates, result)?;
+            needed -= take;
+            if needed == 0 || bucket == last_bucket {
+                break;
+            }
+            bucket += 1;
+            (from, to) = self.real_range(bucket, result)?;
+        }
+
+        // downwards, into the preceding buckets
+        let (mut from, mut to, mut bucket) = (start, insert_at, challenge_bucket);
+        let mut needed = top as u64;
+        loop {
+            let take = needed.min(to - from);
+            self.read_candidates(to - take, take, &mut candidates, result)?;
+            needed -= take;
+            if needed == 0 || bucket == first_bucket {
+                break;
+            }
+            bucket -= 1;
+            (from, to) = self.real_range(bucket, result)?;
+        }
+
+        candidates.sort_by_key(|candidate| candidate.distance);
+        candidates.truncate(top);
+        result.matches = candidates;
+        Ok(())
+    }
+
+    // walks the bit trie of a sorted range: at each bit the half agreeing with the challenge is closer under XOR,
+    // so visiting it first yields records in increasing distance
+    fn xor(
+        &self,
+        low: u64,
+        high: u64,
+        depth: usize,
+        path: [u8; HASH_SIZE],
+        top: usize,
+        result: &mut ClosestResult,
+    ) -> io::Result<()> {
+        if result.matches.len() >= top || low >= high {
+            return Ok(());
+        }
+
+        if high - low <= XOR_LEAF_RECORDS || depth == HASH_SIZE * 8 {
+            let mut leaf = Vec::with_capacity((high - low) as usize);
+            self.read_candidates(low, high - low, &mut leaf, result)?;
+            leaf.sort_by_key(|candidate| candidate.distance);
+            leaf.truncate(top - result.matches.len());
+            result.matches.extend(leaf);
+            return Ok(());
+        }
+
+        // [low, split) has a 0 at this bit, [split, high) a 1
+        let mut upper_path = path;
+        set_bit(&mut upper_path, depth);
+        let upper = Prefix::from_bytes(&upper_path, depth + 1);
+        let split = lower_bound(self.file, low, high, &upper, &mut result.seek_count)?;
+
+        if get_bit(self.challenge, depth) {
+            self.xor(split, high, depth + 1, upper_path, top, result)?;
+            self.xor(low, split, depth + 1, path, top, result)
+        } else {
+            self.xor(low, split, depth + 1, path, top, result)?;
+            self.xor(split, high, depth + 1, upper_path, top, result)
+        }
+    }
+
+    fn read_candidates(
+        &self,
+        first_record: u64,
+        count: u64,
+        candidates: &mut Vec<ClosestMatch>,
+        result: &mut ClosestResult,
+    ) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        result.seek_count += 1;
+        let buffer = read_records(self.file, first_record, count)?;
+        for (i, record) in buffer.chunks_exact(RECORD_SIZE).enumerate() {
+            if is_padding(record) {
+                continue;
+            }
+            let hash: [u8; HASH_SIZE] = record[NONCE_SIZE..].try_into().unwrap();
+            candidates.push(ClosestMatch {
+                index: first_record + i as u64,
+                nonce: record[..NONCE_SIZE].try_into().unwrap(),
+                hash,
+                distance: self.distance.between(&hash, self.challenge),
+            });
+        }
+        Ok(())
+    }
+}
+
+// |a - b| with big-endian byte arrays as numbers
+fn numeric_distance(a: &[u8; HASH_SIZE], b: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    let (larger, smaller) = if a >= b { (a, b) } else { (b, a) };
+    let mut difference = [0u8; HASH_SIZE];
+    let mut borrow = 0i16;
+    for i in (0..HASH_SIZE).rev() {
+        let mut digit = larger[i] as i16 - smaller[i] as i16 - borrow;
+        borrow = 0;
+        if digit < 0 {
+            digit += 256;
+            borrow = 1;
+        }
+        difference[i] = digit as u8;
+    }
+    difference
+}
+
+fn get_bit(bytes: &[u8], bit: usize) -> bool {
+    bytes[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+fn set_bit(bytes: &mut [u8], bit: usize) {
+    bytes[bit / 8] |= 0x80 >> (bit % 8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_generator::generate_hash;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    const PREFIX_SIZE: u32 = 3;
+    const NUM_BUCKETS: usize = 1 << PREFIX_SIZE;
+    const BUCKET_SIZE: usize = 100; // more than XOR_LEAF_RECORDS, so the trie is split
+    const FILLED: [usize; NUM_BUCKETS] = [100, 0, 37, 100, 65, 99, 0, 80];
+
+    // a hand-built sorted vault whose buckets are filled to `FILLED`, removed again when the test is done
+    struct TestVault {
+        path: PathBuf,
+        file: File,
+        footer: VaultFooter,
+        records: Vec<[u8; RECORD_SIZE]>, // the whole vault, padding included
+    }
+
+    impl TestVault {
+        fn new(name: &str) -> Self {
+            let mut buckets = vec![Vec::new(); NUM_BUCKETS];
+            let mut nonce = 0;
+            while buckets
+                .iter()
+                .zip(FILLED)
+                .any(|(bucket, filled)| bucket.len() < filled)
+            {
+                let (bucket, record) = generate_hash(nonce, PREFIX_SIZE as usize);
+                if buckets[bucket as usize].len() < FILLED[bucket as usize] {
+                    let mut bytes = [0u8; RECORD_SIZE];
+                    bytes[..NONCE_SIZE].copy_from_slice(&record.nonce);
+                    bytes[NONCE_SIZE..].copy_from_slice(&record.hash);
+                    buckets[bucket as usize].push(bytes);
+                }
+                nonce += 1;
+            }
+            for bucket in buckets.iter_mut() {
+                bucket.sort_unstable_by(|a, b| a[NONCE_SIZE..].cmp(&b[NONCE_SIZE..]));
+                bucket.resize(BUCKET_SIZE, [0u8; RECORD_SIZE]);
+            }
+            let records = buckets.concat();
+
+            let path = std::env::temp_dir().join(format!(
+                "vault76-closest-test-{}-{}.bin",
+                name,
+                std::process::id()
+            ));
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            file.write_all(&records.concat()).unwrap();
+            let footer = VaultFooter::new(
+                20,
+                PREFIX_SIZE,
+                true,
+                NUM_BUCKETS,
+                BUCKET_SIZE,
+                NUM_BUCKETS * BUCKET_SIZE,
+                0xc105e,
+            );
+            Self {
+                path,
+                file,
+                footer,
+                records,
+            }
+        }
+
+        // the record indices a full scan of `buckets` ranks nearest to `challenge`, nearest first
+        fn scan(
+            &self,
+            challenge: &[u8; HASH_SIZE],
+            distance: Distance,
+            top: usize,
+            buckets: impl Iterator<Item = u64>,
+        ) -> Vec<u64> {
+            let mut ranked: Vec<([u8; HASH_SIZE], u64)> = buckets
+                .flat_map(|bucket| {
+                    let start = bucket as usize * BUCKET_SIZE;
+                    start..start + BUCKET_SIZE
+                })
+                .filter(|&index| !is_padding(&self.records[index]))
+                .map(|index| {
+                    let hash = self.records[index][NONCE_SIZE..].try_into().unwrap();
+                    (distance.between(&hash, challenge), index as u64)
+                })
+                .collect();
+            ranked.sort();
+            ranked.truncate(top);
+            ranked.into_iter().map(|(_, index)| index).collect()
+        }
+
+        // searches with and without a bucket index, which have to agree
+        fn closest(
+            &self,
+            challenge: &[u8; HASH_SIZE],
+            distance: Distance,
+            top: usize,
+            neighbours: u64,
+        ) -> Vec<u64> {
+            let index = BucketIndex::build(&self.file, &self.footer).unwrap();
+            let indexed = find_closest(
+                &self.file,
+                &self.footer,
+                challenge,
+                distance,
+                top,
+                neighbours,
+                Some(&index),
+            )
+            .unwrap();
+            let unindexed = find_closest(
+                &self.file,
+                &self.footer,
+                challenge,
+                distance,
+                top,
+                neighbours,
+                None,
+            )
+            .unwrap();
+            let indices: Vec<u64> = indexed.matches.iter().map(|m| m.index).collect();
+            let unindexed: Vec<u64> = unindexed.matches.iter().map(|m| m.index).collect();
+            assert_eq!(indices, unindexed);
+            indices
+        }
+    }
+
+    impl Drop for TestVault {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    // a challenge that falls into `bucket`, with the rest of its bits taken from a hash no record has
+    fn challenge_in(bucket: u64, seed: u64) -> [u8; HASH_SIZE] {
+        let (_, record) = generate_hash(1 << 40 | seed, 0);
+        let mut challenge = record.hash;
+        challenge[0] = (challenge[0] & 0x1f) | (bucket as u8) << 5;
+        challenge
+    }
+
+    // the buckets numeric distance searches: `neighbours` on either side, within the vault
+    fn numeric_window(bucket: u64, neighbours: u64) -> impl Iterator<Item = u64> {
+        bucket.saturating_sub(neighbours)
+            ..=bucket
+                .saturating_add(neighbours)
+                .min(NUM_BUCKETS as u64 - 1)
+    }
+
+    // the buckets XOR distance searches: every index that differs from `bucket` by at most `neighbours`
+    fn xor_window(bucket: u64, neighbours: u64) -> impl Iterator<Item = u64> {
+        (0..NUM_BUCKETS as u64).filter(move |other| other ^ bucket <= neighbours)
+    }
+
+    #[test]
+    fn numeric_and_xor_distance_rank_differently() {
+        let mut below = [0u8; HASH_SIZE];
+        below[0] = 0x7f;
+        below[1..].fill(0xff);
+        let mut above = [0u8; HASH_SIZE];
+        above[0] = 0x80;
+        let mut one = [0u8; HASH_SIZE];
+        one[HASH_SIZE - 1] = 1;
+        assert_eq!(Distance::Numeric.between(&below, &above), one);
+        assert_eq!(Distance::Numeric.between(&above, &below), one);
+        assert_eq!(Distance::Xor.between(&below, &above), [0xff; HASH_SIZE]);
+    }
+
+    #[test]
+    fn the_nearest_records_match_a_full_scan() {
+        let vault = TestVault::new("scan");
+        for bucket in 0..NUM_BUCKETS as u64 {
+            for seed in 0..4 {
+                let challenge = challenge_in(bucket, bucket * 4 + seed);
+                for neighbours in [0, 1, 2] {
+                    for top in [1, 5, 40] {
+                        assert_eq!(
+                            vault.closest(&challenge, Distance::Numeric, top, neighbours),
+                            vault.scan(
+                                &challenge,
+                                Distance::Numeric,
+                                top,
+                                numeric_window(bucket, neighbours)
+                            ),
+                            "numeric: bucket {} neighbours {} top {}",
+                            bucket,
+                            neighbours,
+                            top
+                        );
+                        assert_eq!(
+                            vault.closest(&challenge, Distance::Xor, top, neighbours),
+                            vault.scan(
+                                &challenge,
+                                Distance::Xor,
+                                top,
+                                xor_window(bucket, neighbours)
+                            ),
+                            "xor: bucket {} neighbours {} top {}",
+                            bucket,
+                            neighbours,
+                            top
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_top_beyond_the_matches_returns_every_record_searched() {
+        let vault = TestVault::new("top");
+        let challenge = challenge_in(3, 7);
+        for distance in [Distance::Numeric, Distance::Xor] {
+            let matches = vault.closest(&challenge, distance, 10_000, 1);
+            let window: Vec<u64> = match distance {
+                Distance::Numeric => numeric_window(3, 1).collect(),
+                Distance::Xor => xor_window(3, 1).collect(),
+            };
+            let filled: usize = window.iter().map(|&bucket| FILLED[bucket as usize]).sum();
+            assert_eq!(matches.len(), filled, "{}", distance.name());
+            assert_eq!(
+                matches,
+                vault.scan(&challenge, distance, 10_000, window.into_iter()),
+                "{}",
+                distance.name()
+            );
+        }
+    }
+
+    #[test]
+    fn neighbours_stop_at_the_first_and_last_bucket() {
+        let vault = TestVault::new("edges");
+        let last = NUM_BUCKETS as u64 - 1;
+        for (bucket, neighbours) in [(0, 1), (0, 3), (last, 1), (last, 3)] {
+            let challenge = challenge_in(bucket, 11);
+            for distance in [Distance::Numeric, Distance::Xor] {
+                let window: Vec<u64> = match distance {
+                    Distance::Numeric => numeric_window(bucket, neighbours).collect(),
+                    Distance::Xor => xor_window(bucket, neighbours).collect(),
+                };
+                assert_eq!(
+                    vault.closest(&challenge, distance, 500, neighbours),
+                    vault.scan(&challenge, distance, 500, window.into_iter()),
+                    "{}: bucket {} neighbours {}",
+                    distance.name(),
+                    bucket,
+                    neighbours
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn huge_neighbour_counts_search_the_whole_vault_without_overflowing() {
+        let vault = TestVault::new("overflow");
+        let last = NUM_BUCKETS as u64 - 1;
+        for bucket in [0, 4, last] {
+            let challenge = challenge_in(bucket, 13);
+            for neighbours in [MAX_NEIGHBOURS, u64::MAX - 1, u64::MAX] {
+                // a top past every record never stops the XOR walk early, so only the clamp ends it
+                for top in [25, 10_000] {
+                    for distance in [Distance::Numeric, Distance::Xor] {
+                        assert_eq!(
+                            vault.closest(&challenge, distance, top, neighbours),
+                            vault.scan(&challenge, distance, top, 0..NUM_BUCKETS as u64),
+                            "{}: bucket {} neighbours {} top {}",
+                            distance.name(),
+                            bucket,
+                            neighbours,
+                            top
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,433 @@
+// this file opens many vaults together as a farm and resolves one challenge against every plot in parallel
+use crate::closest::{self, ClosestMatch, Distance};
+use crate::lookup::{self, hash_to_string, nonce_to_decimal, BucketIndex, SearchStrategy};
+use crate::prefix::Prefix;
+use crate::report::{self, OutputFormat};
+use crate::vault::VaultFooter;
+use crate::{Record, HASH_SIZE};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// one open vault of the farm
+pub struct Plot {
+    pub path: PathBuf,
+    pub file: File,
+    pub footer: VaultFooter,
+    // built on first use by the long-running commands (serve, simulate); one-shot searches only read the buckets
+    // they look at
+    index: OnceLock<BucketIndex>,
+}
+
+impl Plot {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (file, footer) = lookup::open_sorted_vault(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            footer,
+            index: OnceLock::new(),
+        })
+    }
+
+    // the plot's bucket index, built the first time it is asked for
+    pub fn index(&self) -> io::Result<&BucketIndex> {
+        if let Some(index) = self.index.get() {
+            return Ok(index);
+        }
+        let index = BucketIndex::build(&self.file, &self.footer)?;
+        Ok(self.index.get_or_init(|| index))
+    }
+
+    // the bucket index if something already built it; one-shot searches go without rather than read every bucket
+    pub fn cached_index(&self) -> Option<&BucketIndex> {
+        self.index.get()
+    }
+
+    pub fn id_hex(&self) -> String {
+        format!("{:016x}", self.footer.plot_id)
+    }
+}
+
+pub struct Farm {
+    pub plots: Vec<Plot>,
+}
+
+// how a farm is given on the command line
+#[derive(Debug, Clone)]
+pub enum FarmSource {
+    Vaults(Vec<PathBuf>), // every vault listed on its own
+    Path(PathBuf),        // a directory of vaults or a manifest, see `Farm::open`
+}
+
+impl FarmSource {
+    pub fn open(&self) -> io::Result<Farm> {
+        match self {
+            FarmSource::Vaults(paths) => Farm::from_paths(paths),
+            FarmSource::Path(source) => Farm::open(source),
+        }
+    }
+
+    // the directory or manifest, or the listed vaults separated by commas
+    pub fn describe(&self) -> String {
+        match self {
+            FarmSource::Vaults(paths) => paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+            FarmSource::Path(source) => source.display().to_string(),
+        }
+    }
+}
+
+// a match together with the plot (its position in `Farm::plots`) it came from
+pub struct FarmRecord {
+    pub plot: usize,
+    pub record: Record,
+}
+
+pub struct FarmMatch {
+    pub plot: usize,
+    pub found: ClosestMatch,
+}
+
+#[derive(Serialize)]
+struct FarmLookupSummary {
+    farm: String,
+    num_plots: usize,
+    prefix_hex: String,
+    prefix_bits: usize,
+    matches: usize,
+    seeks: usize,
+    duration_us: f64,
+}
+
+#[derive(Serialize, Default)]
+struct FarmLookupRow {
+    plot: String,
+    plot_id: String,
+    nonce: u64,
+    hash_hex: String,
+}
+
+#[derive(Serialize)]
+struct FarmClosestSummary {
+    farm: String,
+    num_plots: usize,
+    challenge_hex: String,
+    challenge_bits: usize,
+    distance_metric: &'static str,
+    top: usize,
+    neighbours: u64,
+    num_matches: usize,
+    buckets_searched: usize,
+    seeks: usize,
+    duration_us: f64,
+}
+
+#[derive(Serialize, Default)]
+struct FarmClosestRow {
+    rank: usize,
+    plot: String,
+    plot_id: String,
+    record_index: u64,
+    nonce: u64,
+    hash_hex: String,
+    distance_hex: String,
+}
+
+impl Farm {
+    // a directory farms every finished vault directly inside it (anything else is skipped with a warning);
+    // any other file is a manifest listing one vault path per line, relative to the manifest's directory
+    pub fn open(source: &Path) -> io::Result<Self> {
+        if source.is_dir() {
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(source)? {
+                let path = entry?.path();
+                if path.is_file() {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+
+            let opened = paths
+                .par_iter()
+                .map(|path| (path, Plot::open(path)))
+                .collect::<Vec<_>>();
+            let mut plots = Vec::with_capacity(opened.len());
+            for (path, plot) in opened {
+                match plot {
+                    Ok(plot) => plots.push(plot),
+                    Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+                }
+            }
+            if plots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no finished, sorted vaults in {}", source.display()),
+                ));
+            }
+            Ok(Self { plots })
+        } else {
+            Self::from_paths(&read_manifest(source)?)
+        }
+    }
+
+    // every listed vault must open; a farm missing a plot it was told about is an error
+    pub fn from_paths(paths: &[PathBuf]) -> io::Result<Self> {
+        if paths.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a farm needs at least one vault",
+            ));
+        }
+        let plots = paths
+            .par_iter()
+            .map(|path| {
+                Plot::open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+            })
+            .collect::<io::Result<Vec<Plot>>>()?;
+        Ok(Self { plots })
+    }
+
+    pub fn num_records(&self) -> u64 {
+        self.plots.iter().map(|plot| plot.footer.num_records).sum()
+    }
+
+    // every record of every plot whose hash starts with `prefix`, in plot order, plus the seeks over all plots
+    pub fn find_by_prefix(
+        &self,
+        prefix: &Prefix,
+        strategy: SearchStrategy,
+    ) -> io::Result<(Vec<FarmRecord>, usize)> {
+        self.find_by_prefix_limited(prefix, strategy, usize::MAX)
+    }
+
+    // like `find_by_prefix`, but each plot stops past `limit` matches; more than `limit` records back means too many
+    pub fn find_by_prefix_limited(
+        &self,
+        prefix: &Prefix,
+        strategy: SearchStrategy,
+        limit: usize,
+    ) -> io::Result<(Vec<FarmRecord>, usize)> {
+        let per_plot = self
+            .plots
+            .par_iter()
+            .map(|plot| {
+                lookup::find_by_prefix_limited(&plot.file, &plot.footer, prefix, strategy, limit)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut records = Vec::new();
+        let mut seek_count = 0;
+        for (plot, (found, seeks)) in per_plot.into_iter().enumerate() {
+            records.extend(found.into_iter().map(|record| FarmRecord { plot, record }));
+            seek_count += seeks;
+        }
+        Ok((records, seek_count))
+    }
+
+    // the `top` records nearest to `challenge` over the whole farm, nearest first, with buckets searched and seeks
+    pub fn find_closest(
+        &self,
+        challenge: &[u8; HASH_SIZE],
+        distance: Distance,
+        top: usize,
+        neighbours: u64,
+    ) -> io::Result<(Vec<FarmMatch>, usize, usize)> {
+        let per_plot = self
+            .plots
+            .par_iter()
+            .map(|plot| {
+                closest::find_closest(
+                    &plot.file,
+                    &plot.footer,
+                    challenge,
+                    distance,
+                    top,
+                    neighbours,
+                    plot.cached_index(),
+                )
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut matches = Vec::new();
+        let mut buckets_searched = 0;
+        let mut seek_count = 0;
+        for (plot, result) in per_plot.into_iter().enumerate() {
+            matches.extend(
+                result
+                    .matches
+                    .into_iter()
+                    .map(|found| FarmMatch { plot, found }),
+            );
+            buckets_searched += result.buckets_searched;
+            seek_count += result.seek_count;
+        }
+        // ties go to the earlier plot, so the answer does not depend on thread timing
+        matches.sort_by_key(|found| (found.found.distance, found.plot));
+        matches.truncate(top);
+        Ok((matches, buckets_searched, seek_count))
+    }
+}
+
+// one vault path per line; blank lines and lines starting with '#' are skipped
+fn read_manifest(manifest: &Path) -> io::Result<Vec<PathBuf>> {
+    let base = manifest.parent().unwrap_or_else(|| Path::new("."));
+    let mut paths = Vec::new();
+    for line in io::BufReader::new(File::open(manifest)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            paths.push(base.join(line));
+        }
+    }
+    Ok(paths)
+}
+
+pub fn lookup_by_prefix(
+    source: &Path,
+    prefix: &Prefix,
+    strategy: SearchStrategy,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let farm = Farm::open(source)?;
+
+    let start_time = Instant::now();
+    let (records, seek_count) = farm.find_by_prefix(prefix, strategy)?;
+    let duration = start_time.elapsed();
+
+    if !format.is_table() {
+        let summary = FarmLookupSummary {
+            farm: source.display().to_string(),
+            num_plots: farm.plots.len(),
+            prefix_hex: prefix.hex(),
+            prefix_bits: prefix.bits(),
+            matches: records.len(),
+            seeks: seek_count,
+            duration_us: duration.as_secs_f64() * 1e6,
+        };
+        let rows = records
+            .iter()
+            .map(|found| FarmLookupRow {
+                plot: farm.plots[found.plot].path.display().to_string(),
+                plot_id: farm.plots[found.plot].id_hex(),
+                nonce: nonce_to_decimal(&found.record.nonce),
+                hash_hex: hash_to_string(&found.record.hash),
+            })
+            .collect::<Vec<FarmLookupRow>>();
+        return report::emit_rows(format, "farm_lookup", &summary, "records", &rows);
+    }
+
+    if !records.is_empty() {
+        println!(
+            "{:<16} | {:<16} | {:<64}",
+            "Plot ID", "Nonce (Decimal)", "Hash (Hex)"
+        );
+        println!("{}", "-".repeat(107));
+        for found in &records {
+            println!(
+                "{} | {:<16} | {}",
+                farm.plots[found.plot].id_hex(),
+                nonce_to_decimal(&found.record.nonce),
+                hash_to_string(&found.record.hash)
+            );
+        }
+    } else {
+        println!("No records found with the specified prefix {}", prefix);
+    }
+
+    println!(
+        "Searched {} plots ({} records), search duration: {:?}, Seek operations: {}",
+        farm.plots.len(),
+        farm.num_records(),
+        duration,
+        seek_count
+    );
+    Ok(())
+}
+
+pub fn lookup_closest(
+    source: &Path,
+    challenge: &Prefix,
+    distance: Distance,
+    top: usize,
+    neighbours: u64,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let farm = Farm::open(source)?;
+
+    let start_time = Instant::now();
+    let (matches, buckets_searched, seek_count) =
+        farm.find_closest(challenge.bytes(), distance, top, neighbours)?;
+    let duration = start_time.elapsed();
+
+    if !format.is_table() {
+        let summary = FarmClosestSummary {
+            farm: source.display().to_string(),
+            num_plots: farm.plots.len(),
+            challenge_hex: challenge.hex(),
+            challenge_bits: challenge.bits(),
+            distance_metric: distance.name(),
+            top,
+            neighbours,
+            num_matches: matches.len(),
+            buckets_searched,
+            seeks: seek_count,
+            duration_us: duration.as_secs_f64() * 1e6,
+        };
+        let rows = matches
+            .iter()
+            .enumerate()
+            .map(|(rank, found)| FarmClosestRow {
+                rank: rank + 1,
+                plot: farm.plots[found.plot].path.display().to_string(),
+                plot_id: farm.plots[found.plot].id_hex(),
+                record_index: found.found.index,
+                nonce: nonce_to_decimal(&found.found.nonce),
+                hash_hex: hash_to_string(&found.found.hash),
+                distance_hex: hash_to_string(&found.found.distance),
+            })
+            .collect::<Vec<FarmClosestRow>>();
+        return report::emit_rows(format, "farm_closest", &summary, "matches", &rows);
+    }
+
+    if matches.is_empty() {
+        println!(
+            "No records in the {} bucket(s) searched for challenge {}",
+            buckets_searched, challenge
+        );
+    } else {
+        println!(
+            "{:<4} | {:<16} | {:<12} | {:<16} | {:<52} | {:<52}",
+            "Rank", "Plot ID", "Record", "Nonce (Decimal)", "Hash (Hex)", "Distance (Hex)"
+        );
+        println!("{}", "-".repeat(167));
+        for (rank, found) in matches.iter().enumerate() {
+            println!(
+                "{:<4} | {} | {:<12} | {:<16} | {} | {}",
+                rank + 1,
+                farm.plots[found.plot].id_hex(),
+                found.found.index,
+                nonce_to_decimal(&found.found.nonce),
+                hash_to_string(&found.found.hash),
+                hash_to_string(&found.found.distance)
+            );
+        }
+    }
+
+    println!(
+        "Searched {} plots, search duration: {:?}, Seek operations: {}, Buckets searched: {}",
+        farm.plots.len(),
+        duration,
+        seek_count,
+        buckets_searched
+    );
+    Ok(())
+}
@@ -13,6 +13,25 @@ pub fn generate_hash(nonce: u64, prefix_length: usize) -> (u64, Record) {
     let hash = hasher.finalize();
     let hash_bytes = hash.as_bytes();
 
+    let prefix = extract_prefix(hash_bytes, prefix_length);
+
+    // return a tuple containing our extracted prefix and the Record of each nonce/hash pair
+    (
+        prefix,
+        Record {
+            nonce: nonce_bytes[2..8].try_into().unwrap(),
+            hash: hash_bytes[0..HASH_SIZE].try_into().unwrap(),
+        },
+    )
+}
+
+// the leading `prefix_length` bits of a hash; this is the bucket a record with that hash belongs to
+#[inline]
+pub fn extract_prefix(hash_bytes: &[u8], prefix_length: usize) -> u64 {
+    if prefix_length == 0 {
+        return 0;
+    }
+
     // prefix of desired length is extracted using bitshifting from left to right
     let mut prefix = 0u64;
     let mut bits_processed = 0;
@@ -30,12 +49,5 @@ pub fn generate_hash(nonce: u64, prefix_length: usize) -> (u64, Record) {
 
     prefix &= (1u64 << prefix_length) - 1;
 
-    // return a tuple containing our extracted prefix and the Record of each nonce/hash pair
-    (
-        prefix,
-        Record {
-            nonce: nonce_bytes[2..8].try_into().unwrap(),
-            hash: hash_bytes[0..HASH_SIZE].try_into().unwrap(),
-        },
-    )
+    prefix
 }
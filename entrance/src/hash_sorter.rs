@@ -1,61 +1,513 @@
 use std::{
-    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    sync::RwLock,
+    cmp::Ordering,
+    collections::BinaryHeap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::fs::FileExt,
+    path::Path,
 };
 
-use crate::{NONCE_SIZE, RECORD_SIZE};
+use crate::lookup;
+use crate::memory_budget::MemoryBudget;
+use crate::{Record, HASH_SIZE, NONCE_SIZE, RECORD_SIZE};
 
-pub fn sort_hashes(
-    path: &String,
+const RADIX_CUTOFF: usize = 64; // below this many records, a comparison sort beats another radix pass
+
+// how the records inside a bucket get sorted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortAlgorithm {
+    Comparison,
+    Radix,
+}
+
+// anything that can be sorted by its hash: records in memory and raw records read back from disk
+pub trait HashKey {
+    fn hash_bytes(&self) -> &[u8];
+    fn is_padding(&self) -> bool;
+}
+
+impl HashKey for Record {
+    fn hash_bytes(&self) -> &[u8] {
+        &self.hash
+    }
+
+    fn is_padding(&self) -> bool {
+        self.nonce
+            .iter()
+            .chain(self.hash.iter())
+            .all(|&byte| byte == 0)
+    }
+}
+
+impl HashKey for [u8; RECORD_SIZE] {
+    fn hash_bytes(&self) -> &[u8] {
+        &self[NONCE_SIZE..]
+    }
+
+    fn is_padding(&self) -> bool {
+        lookup::is_padding(self)
+    }
+}
+
+// sorts records by hash; `common_bytes` leading hash bytes are known to be equal across the slice and are skipped
+// zero padding would sort first by hash, but lookups expect it at the end of a bucket, so it is moved there first
+pub fn sort_records<T: HashKey>(records: &mut [T], algorithm: SortAlgorithm, common_bytes: usize) {
+    let filled = move_padding_to_end(records);
+    let records = &mut records[..filled];
+    match algorithm {
+        SortAlgorithm::Comparison => {
+            records.sort_unstable_by(|a, b| a.hash_bytes().cmp(b.hash_bytes()))
+        }
+        SortAlgorithm::Radix => radix_sort(records, common_bytes.min(HASH_SIZE)),
+    }
+}
+
+// moves every padding record behind the records holding a hash; returns how many hold one
+fn move_padding_to_end<T: HashKey>(records: &mut [T]) -> usize {
+    let mut filled = 0;
+    for i in 0..records.len() {
+        if !records[i].is_padding() {
+            records.swap(filled, i);
+            filled += 1;
+        }
+    }
+    filled
+}
+
+// in-place MSD radix sort (American flag sort): one byte per pass, recursing into each byte value
+// hashes that tie on every byte simply end up next to each other once the last byte is reached
+fn radix_sort<T: HashKey>(records: &mut [T], byte: usize) {
+    if byte >= HASH_SIZE || records.len() < 2 {
+        return;
+    }
+    if records.len() < RADIX_CUTOFF {
+        records.sort_unstable_by(|a, b| a.hash_bytes()[byte..].cmp(&b.hash_bytes()[byte..]));
+        return;
+    }
+
+    let mut counts = [0usize; 256];
+    for record in records.iter() {
+        counts[record.hash_bytes()[byte] as usize] += 1;
+    }
+
+    // every record shares this byte (e.g. the bucket prefix), so go straight to the next one
+    if counts.contains(&records.len()) {
+        radix_sort(records, byte + 1);
+        return;
+    }
+
+    let mut starts = [0usize; 256];
+    let mut ends = [0usize; 256];
+    let mut total = 0;
+    for digit in 0..256 {
+        starts[digit] = total;
+        total += counts[digit];
+        ends[digit] = total;
+    }
+
+    // swap every record into the region of its digit
+    let mut next = starts;
+    for digit in 0..256 {
+        while next[digit] < ends[digit] {
+            let record_digit = records[next[digit]].hash_bytes()[byte] as usize;
+            if record_digit == digit {
+                next[digit] += 1;
+            } else {
+                records.swap(next[digit], next[record_digit]);
+                next[record_digit] += 1;
+            }
+        }
+    }
+
+    for digit in 0..256 {
+        radix_sort(&mut records[starts[digit]..ends[digit]], byte + 1);
+    }
+}
+
+// reads one byte range of a file through positional reads, so all buckets can share a single file handle
+struct RangeReader<'a> {
+    file: &'a File,
+    position: u64,
+    end: u64,
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = (self.end - self.position).min(buf.len() as u64) as usize;
+        if remaining == 0 {
+            return Ok(0);
+        }
+        let bytes_read = self.file.read_at(&mut buf[..remaining], self.position)?;
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+// writes forward from a starting offset through positional writes
+struct RangeWriter<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl Write for RangeWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.file.write_at(buf, self.position)?;
+        self.position += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn range_reader(file: &File, start: u64, len: u64, capacity: usize) -> BufReader<RangeReader<'_>> {
+    BufReader::with_capacity(
+        capacity,
+        RangeReader {
+            file,
+            position: start,
+            end: start + len,
+        },
+    )
+}
+
+fn range_writer(file: &File, start: u64) -> BufWriter<RangeWriter<'_>> {
+    BufWriter::new(RangeWriter {
+        file,
+        position: start,
+    })
+}
+
+// sorts one disk bucket while staying inside the memory budget
+// buckets that fit one thread's share of the budget are sorted in RAM; bigger ones fall back to an external sort
+// through `scratch_path`, so no thread waits on the others for memory
+pub fn sort_bucket(
+    file: &File,
+    scratch_path: &Path,
     bucket_index: usize,
     bucket_size: usize,
-    offsets: &RwLock<Vec<usize>>,
-) {
-    let offsets = offsets.read().unwrap(); // Acquire write lock on offsets
+    algorithm: SortAlgorithm,
+    common_bytes: usize,
+    budget: &MemoryBudget,
+) -> io::Result<()> {
+    let start = (bucket_index * bucket_size * RECORD_SIZE) as u64;
+    let bucket_bytes = bucket_size * RECORD_SIZE;
 
-    let file = std::fs::OpenOptions::new()
+    let thread_share = budget.share(rayon::current_num_threads());
+
+    if bucket_bytes <= thread_share {
+        let _permit = budget.acquire(bucket_bytes);
+        return sort_hashes(file, start, bucket_size, algorithm, common_bytes);
+    }
+
+    // pass 1: sort runs small enough that every thread can hold one at the same time
+    let run_records = (thread_share / RECORD_SIZE).max(1);
+    let mut run_lengths = Vec::new();
+    let mut run_start = 0;
+    while run_start < bucket_size {
+        let run_length = run_records.min(bucket_size - run_start);
+        let _permit = budget.acquire(run_length * RECORD_SIZE);
+        sort_hashes(
+            file,
+            start + (run_start * RECORD_SIZE) as u64,
+            run_length,
+            algorithm,
+            common_bytes,
+        )?;
+        run_lengths.push(run_length);
+        run_start += run_length;
+    }
+
+    // pass 2: merge the runs into a scratch file, since they can't be merged in place
+    let scratch = OpenOptions::new()
         .read(true)
         .write(true)
-        .open(&path)
-        .expect("Error opening file");
+        .create(true)
+        .truncate(true)
+        .open(scratch_path)?;
+    {
+        let _permit = budget.acquire(run_lengths.len() * MERGE_BUFFER_SIZE);
+        merge_runs(file, start, &run_lengths, &scratch, 0)?;
+    }
 
-    let start = offsets[bucket_index] as u64;
-    let end = start + (bucket_size * RECORD_SIZE) as u64;
+    // pass 3: stream the merged bucket back into its place in the vault
+    let _permit = budget.acquire(MERGE_BUFFER_SIZE);
+    let mut reader = range_reader(&scratch, 0, bucket_bytes as u64, MERGE_BUFFER_SIZE);
+    let mut writer = range_writer(file, start);
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
 
-    let mut reader = BufReader::new(&file);
-    reader
-        .seek(SeekFrom::Start(start))
-        .expect("Error seeking to start of bucket");
+    drop(scratch);
+    std::fs::remove_file(scratch_path)
+}
+
+// reads `num_records` records starting at `start`, sorts them in RAM and writes them back in place
+fn sort_hashes(
+    file: &File,
+    start: u64,
+    num_records: usize,
+    algorithm: SortAlgorithm,
+    common_bytes: usize,
+) -> io::Result<()> {
+    let mut reader = range_reader(
+        file,
+        start,
+        (num_records * RECORD_SIZE) as u64,
+        MERGE_BUFFER_SIZE,
+    );
 
-    let mut bucket_records = Vec::with_capacity(bucket_size);
+    let mut bucket_records = Vec::with_capacity(num_records);
     let mut buffer = [0; RECORD_SIZE];
 
-    while reader
-        .stream_position()
-        .expect("Error getting stream position")
-        < end
-    {
-        if let Ok(_) = reader.read_exact(&mut buffer) {
-            bucket_records.push(buffer.clone());
-        } else {
-            break;
-        }
+    for _ in 0..num_records {
+        reader.read_exact(&mut buffer)?;
+        bucket_records.push(buffer);
     }
 
     // Sort the records in the current bucket
-    bucket_records.sort_unstable_by(|a, b| a[NONCE_SIZE..].cmp(&b[NONCE_SIZE..]));
+    sort_records(&mut bucket_records, algorithm, common_bytes);
 
-    let mut writer = BufWriter::new(&file);
-
-    writer
-        .seek(SeekFrom::Start(start))
-        .expect("Error seeking to start of bucket");
+    let mut writer = range_writer(file, start);
 
     for record in bucket_records {
-        writer
-            .write_all(&record)
-            .expect("Error writing record to file");
+        writer.write_all(&record)?;
+    }
+
+    writer.flush()
+}
+
+const MERGE_BUFFER_SIZE: usize = 64 * 1024; // read buffer per sorted run during a merge
+
+// one record waiting in the merge heap, ordered by hash so the smallest hash is popped first;
+// padding is popped last, after every record holding a hash
+struct MergeEntry {
+    record: [u8; RECORD_SIZE],
+    run_index: usize,
+}
+
+impl MergeEntry {
+    fn key(&self) -> (bool, &[u8]) {
+        (self.record.is_padding(), self.record.hash_bytes())
     }
+}
+
+impl Ord for MergeEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, because BinaryHeap is a max-heap
+        other.key().cmp(&self.key())
+    }
+}
 
-    writer.flush().expect("Error flushing writer");
+impl PartialOrd for MergeEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for MergeEntry {}
+
+// merges the already sorted runs of one disk bucket from `src` into the same place in `dst`
+// only one small read buffer per run is held in memory, instead of the whole bucket
+pub fn merge_sorted_runs(
+    src: &File,
+    dst: &File,
+    bucket_index: usize,
+    bucket_size: usize,
+    run_lengths: &[usize],
+    budget: &MemoryBudget,
+) -> io::Result<()> {
+    let bucket_start = (bucket_index * bucket_size * RECORD_SIZE) as u64;
+    let _permit = budget.acquire(run_lengths.len() * MERGE_BUFFER_SIZE);
+    merge_runs(src, bucket_start, run_lengths, dst, bucket_start)
+}
+
+fn merge_runs(
+    src: &File,
+    src_start: u64,
+    run_lengths: &[usize],
+    dst: &File,
+    dst_start: u64,
+) -> io::Result<()> {
+    let mut runs = Vec::with_capacity(run_lengths.len());
+    let mut run_start = src_start;
+    for &run_length in run_lengths {
+        let run_bytes = (run_length * RECORD_SIZE) as u64;
+        runs.push(range_reader(src, run_start, run_bytes, MERGE_BUFFER_SIZE));
+        run_start += run_bytes;
+    }
+
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (run_index, run) in runs.iter_mut().enumerate() {
+        if let Some(record) = read_record(run) {
+            heap.push(MergeEntry { record, run_index });
+        }
+    }
+
+    let mut writer = range_writer(dst, dst_start);
+
+    while let Some(MergeEntry { record, run_index }) = heap.pop() {
+        writer.write_all(&record)?;
+        if let Some(record) = read_record(&mut runs[run_index]) {
+            heap.push(MergeEntry { record, run_index });
+        }
+    }
+
+    writer.flush()
+}
+
+fn read_record<R: Read>(reader: &mut R) -> Option<[u8; RECORD_SIZE]> {
+    let mut buffer = [0; RECORD_SIZE];
+    reader.read_exact(&mut buffer).ok()?;
+    Some(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_generator;
+
+    // `count` records whose hashes all start with `first_byte`, as if they came from one bucket
+    fn bucket_records(first_byte: u8, count: usize) -> Vec<[u8; RECORD_SIZE]> {
+        (0u64..)
+            .map(|nonce| {
+                let (_, record) = hash_generator::generate_hash(nonce, 0);
+                let mut bytes = [0u8; RECORD_SIZE];
+                bytes[..NONCE_SIZE].copy_from_slice(&record.nonce);
+                bytes[NONCE_SIZE..].copy_from_slice(&record.hash);
+                bytes
+            })
+            .filter(|bytes| bytes[NONCE_SIZE] == first_byte)
+            .take(count)
+            .collect()
+    }
+
+    // a padding slot after every second record, as in a bucket that was not filled completely
+    fn with_padding(records: &[[u8; RECORD_SIZE]]) -> Vec<[u8; RECORD_SIZE]> {
+        records
+            .iter()
+            .enumerate()
+            .flat_map(|(i, record)| match i % 2 {
+                0 => vec![*record, [0u8; RECORD_SIZE]],
+                _ => vec![*record],
+            })
+            .collect()
+    }
+
+    fn assert_sorted_then_padding(records: &[[u8; RECORD_SIZE]], filled: usize) {
+        assert!(records[..filled].iter().all(|record| !record.is_padding()));
+        assert!(records[filled..].iter().all(|record| record.is_padding()));
+        assert!(records[..filled]
+            .windows(2)
+            .all(|pair| pair[0].hash_bytes() <= pair[1].hash_bytes()));
+    }
+
+    #[test]
+    fn padding_sorts_after_every_hash() {
+        let records = bucket_records(0x5a, 300);
+        let padded = with_padding(&records);
+        let filled = padded.iter().filter(|record| !record.is_padding()).count();
+        assert!(filled < padded.len());
+
+        for algorithm in [SortAlgorithm::Comparison, SortAlgorithm::Radix] {
+            for common_bytes in [0, 1] {
+                let mut sorted = padded.clone();
+                sort_records(&mut sorted, algorithm, common_bytes);
+                assert_sorted_then_padding(&sorted, filled);
+            }
+        }
+    }
+
+    #[test]
+    fn radix_sort_matches_the_comparison_sort() {
+        // around the cutoff where radix passes hand over to a comparison sort, and well past it
+        for count in [
+            0,
+            1,
+            RADIX_CUTOFF - 1,
+            RADIX_CUTOFF,
+            RADIX_CUTOFF + 1,
+            1000,
+            5000,
+        ] {
+            let records = bucket_records(0x3c, count);
+            for common_bytes in [0, 1] {
+                let mut comparison = records.clone();
+                sort_records(&mut comparison, SortAlgorithm::Comparison, common_bytes);
+                let mut radix = records.clone();
+                sort_records(&mut radix, SortAlgorithm::Radix, common_bytes);
+                assert_eq!(
+                    comparison, radix,
+                    "count={} common_bytes={}",
+                    count, common_bytes
+                );
+                assert_sorted_then_padding(&radix, count);
+            }
+        }
+    }
+
+    #[test]
+    fn radix_sort_handles_long_shared_prefixes_and_records() {
+        // hashes equal in their first bytes make the radix sort recurse deep before they differ
+        let records = || {
+            (0u64..2000)
+                .map(|nonce| {
+                    let (_, mut record) = hash_generator::generate_hash(nonce, 0);
+                    record.hash[..4].copy_from_slice(&[0x12, 0x34, 0x56, (nonce % 3) as u8]);
+                    record
+                })
+                .collect::<Vec<Record>>()
+        };
+
+        let mut comparison = records();
+        sort_records(&mut comparison, SortAlgorithm::Comparison, 1);
+        let mut radix = records();
+        sort_records(&mut radix, SortAlgorithm::Radix, 1);
+        assert!(comparison
+            .iter()
+            .zip(&radix)
+            .all(|(a, b)| a.nonce == b.nonce && a.hash == b.hash));
+        assert!(radix.windows(2).all(|pair| pair[0].hash <= pair[1].hash));
+    }
+
+    #[test]
+    fn external_sort_keeps_padding_at_the_end() {
+        let padded = with_padding(&bucket_records(0xa5, 500));
+        let filled = padded.iter().filter(|record| !record.is_padding()).count();
+        let path =
+            std::env::temp_dir().join(format!("vault76-sort-test-{}.bin", std::process::id()));
+        std::fs::write(&path, padded.concat()).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        // a budget far smaller than the bucket forces sorted runs merged through the scratch file
+        let budget = MemoryBudget::new(64 * RECORD_SIZE);
+        sort_bucket(
+            &file,
+            &path.with_extension("bucket0"),
+            0,
+            padded.len(),
+            SortAlgorithm::Comparison,
+            1,
+            &budget,
+        )
+        .unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let sorted = bytes
+            .chunks_exact(RECORD_SIZE)
+            .map(|record| record.try_into().unwrap())
+            .collect::<Vec<[u8; RECORD_SIZE]>>();
+        assert_sorted_then_padding(&sorted, filled);
+    }
 }
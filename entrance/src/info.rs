@@ -0,0 +1,253 @@
+// this file describes a finished vault: the parameters in its footer, how full its buckets are, its smallest and
+// largest hash, and how much of the file holds records rather than padding
+use crate::lookup::{self, hash_to_string, BucketIndex};
+use crate::report::{self, OutputFormat};
+use crate::vault::{self, VaultFooter, FOOTER_SIZE};
+use crate::{HASH_SIZE, NONCE_SIZE, RECORD_SIZE};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+const SCAN_CHUNK_RECORDS: u64 = 64 * 1024; // records read per call when an unsorted vault has to be scanned
+const FILL_BINS: usize = 10; // histogram bins of 10% each, plus one for completely full buckets
+
+type Hash = [u8; HASH_SIZE];
+
+// the records holding a hash in every bucket, plus the smallest and largest of those hashes
+struct BucketScan {
+    fills: Vec<u64>,
+    min_hash: Option<Hash>,
+    max_hash: Option<Hash>,
+}
+
+#[derive(Serialize)]
+struct InfoSummary {
+    vault: String,
+    plot_id: String,
+    k: u32,
+    sorted: bool,
+    prefix_bits: u32,
+    num_buckets: u64,
+    bucket_size: u64,
+    num_records: u64,      // record slots, padding included
+    expected_records: u64, // 2^k
+    hash_records: u64,
+    padding_records: u64,
+    bucket_fill_min: u64,
+    bucket_fill_mean: f64,
+    bucket_fill_max: u64,
+    min_hash_hex: Option<String>,
+    max_hash_hex: Option<String>,
+    file_size_bytes: u64,
+    efficiency: f64, // share of the file's bytes that hold a hash record
+    full_scan: bool, // unsorted vaults are read in full, sorted ones only around their bucket ends
+    duration_s: f64,
+}
+
+// buckets whose fill (records holding a hash, over bucket_size) falls in [fill_from_pct, fill_to_pct)
+#[derive(Serialize, Default)]
+struct FillRow {
+    fill_from_pct: u32,
+    fill_to_pct: u32,
+    buckets: u64,
+}
+
+pub fn show_info(path: &Path, format: OutputFormat) -> io::Result<()> {
+    let (file, footer) = vault::open_vault(path)?;
+    let file_size = file.metadata()?.len();
+
+    let start_scan = Instant::now();
+    let scan = if footer.sorted {
+        scan_sorted(&file, &footer)?
+    } else {
+        scan_unsorted(&file, &footer)?
+    };
+    let duration = start_scan.elapsed();
+
+    let hash_records = scan.fills.iter().sum::<u64>();
+    let rows = fill_histogram(&scan.fills, footer.bucket_size);
+    let summary = InfoSummary {
+        vault: path.display().to_string(),
+        plot_id: format!("{:016x}", footer.plot_id),
+        k: footer.k,
+        sorted: footer.sorted,
+        prefix_bits: footer.prefix_size,
+        num_buckets: footer.num_buckets,
+        bucket_size: footer.bucket_size,
+        num_records: footer.num_records,
+        expected_records: 1u64 << footer.k,
+        hash_records,
+        padding_records: footer.num_records - hash_records,
+        bucket_fill_min: scan.fills.iter().copied().min().unwrap_or_default(),
+        bucket_fill_mean: hash_records as f64 / scan.fills.len().max(1) as f64,
+        bucket_fill_max: scan.fills.iter().copied().max().unwrap_or_default(),
+        min_hash_hex: scan.min_hash.as_ref().map(hash_to_string),
+        max_hash_hex: scan.max_hash.as_ref().map(hash_to_string),
+        file_size_bytes: file_size,
+        efficiency: (hash_records * RECORD_SIZE as u64) as f64 / file_size.max(1) as f64,
+        full_scan: !footer.sorted,
+        duration_s: duration.as_secs_f64(),
+    };
+
+    if !format.is_table() {
+        return report::emit_rows(format, "info", &summary, "bucket_fill", &rows);
+    }
+
+    println!("Vault:         {}", summary.vault);
+    println!("Plot ID:       {}", summary.plot_id);
+    println!("k:             {}", summary.k);
+    println!(
+        "Sorted:        {}",
+        if summary.sorted { "yes" } else { "no" }
+    );
+    println!("Prefix:        {} bits", summary.prefix_bits);
+    println!(
+        "Buckets:       {} of {} records",
+        summary.num_buckets, summary.bucket_size
+    );
+    println!(
+        "Records:       {} slots, {} hold a hash, {} are padding (2^{} = {} expected)",
+        summary.num_records,
+        summary.hash_records,
+        summary.padding_records,
+        summary.k,
+        summary.expected_records
+    );
+    println!(
+        "Smallest hash: {}",
+        summary.min_hash_hex.as_deref().unwrap_or("-")
+    );
+    println!(
+        "Largest hash:  {}",
+        summary.max_hash_hex.as_deref().unwrap_or("-")
+    );
+    println!(
+        "Efficiency:    {:.4}% of the file's {} bytes hold records ({} bytes of footer and {} of padding)",
+        summary.efficiency * 100.0,
+        summary.file_size_bytes,
+        FOOTER_SIZE,
+        summary.padding_records * RECORD_SIZE as u64
+    );
+    println!(
+        "Bucket fill:   min {}, mean {:.1}, max {} records",
+        summary.bucket_fill_min, summary.bucket_fill_mean, summary.bucket_fill_max
+    );
+    println!("{:<10} | {:<10}", "Fill", "Buckets");
+    println!("{}", "-".repeat(23));
+    for row in &rows {
+        let range = if row.fill_from_pct == 100 {
+            "100%".to_string()
+        } else {
+            format!("{}-{}%", row.fill_from_pct, row.fill_to_pct)
+        };
+        println!("{:<10} | {:<10}", range, row.buckets);
+    }
+    println!(
+        "Scanned in {:?}{}",
+        duration,
+        if summary.full_scan {
+            " (full scan, the vault is unsorted)"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+// padding only ever sits at the end of a sorted bucket, so the bucket index already knows every fill,
+// and the smallest and largest hash are the first and last record holding one
+fn scan_sorted(file: &File, footer: &VaultFooter) -> io::Result<BucketScan> {
+    let index = BucketIndex::build(file, footer)?;
+    let ranges = (0..index.num_buckets() as u64)
+        .map(|bucket| index.range(bucket))
+        .collect::<Vec<(u64, u64)>>();
+    let mut filled = ranges.iter().filter(|(start, end)| start < end);
+    let read_hash = |record: u64| -> io::Result<Hash> {
+        Ok(lookup::read_records(file, record, 1)?[NONCE_SIZE..]
+            .try_into()
+            .unwrap())
+    };
+
+    Ok(BucketScan {
+        fills: ranges.iter().map(|(start, end)| end - start).collect(),
+        min_hash: filled
+            .next()
+            .map(|&(start, _)| read_hash(start))
+            .transpose()?,
+        max_hash: filled
+            .next_back()
+            .map(|&(_, end)| read_hash(end - 1))
+            .transpose()?,
+    })
+}
+
+// without order, padding can be anywhere in a bucket, so every record is read
+fn scan_unsorted(file: &File, footer: &VaultFooter) -> io::Result<BucketScan> {
+    let bucket_size = footer.bucket_size.max(1);
+    let num_buckets = footer.num_records.div_ceil(bucket_size);
+    let per_bucket = (0..num_buckets)
+        .into_par_iter()
+        .map(|bucket| {
+            let start = bucket * bucket_size;
+            let end = (start + bucket_size).min(footer.num_records);
+            let mut fill = 0;
+            let mut min_hash: Option<Hash> = None;
+            let mut max_hash: Option<Hash> = None;
+            for first in (start..end).step_by(SCAN_CHUNK_RECORDS as usize) {
+                let records =
+                    lookup::read_records(file, first, SCAN_CHUNK_RECORDS.min(end - first))?;
+                for record in records.chunks_exact(RECORD_SIZE) {
+                    if lookup::is_padding(record) {
+                        continue;
+                    }
+                    let hash: Hash = record[NONCE_SIZE..].try_into().unwrap();
+                    fill += 1;
+                    if min_hash.is_none_or(|min| hash < min) {
+                        min_hash = Some(hash);
+                    }
+                    if max_hash.is_none_or(|max| hash > max) {
+                        max_hash = Some(hash);
+                    }
+                }
+            }
+            Ok((fill, min_hash, max_hash))
+        })
+        .collect::<io::Result<Vec<(u64, Option<Hash>, Option<Hash>)>>>()?;
+
+    Ok(BucketScan {
+        fills: per_bucket.iter().map(|&(fill, _, _)| fill).collect(),
+        min_hash: per_bucket.iter().filter_map(|&(_, min, _)| min).min(),
+        max_hash: per_bucket.iter().filter_map(|&(_, _, max)| max).max(),
+    })
+}
+
+// FILL_BINS bins of equal width from 0% up to (not including) 100%, then one for full buckets
+fn fill_histogram(fills: &[u64], bucket_size: u64) -> Vec<FillRow> {
+    let width = 100 / FILL_BINS as u32;
+    let mut rows = (0..FILL_BINS as u32)
+        .map(|bin| FillRow {
+            fill_from_pct: bin * width,
+            fill_to_pct: (bin + 1) * width,
+            buckets: 0,
+        })
+        .collect::<Vec<FillRow>>();
+    rows.push(FillRow {
+        fill_from_pct: 100,
+        fill_to_pct: 100,
+        buckets: 0,
+    });
+
+    for &fill in fills {
+        let bin = if fill >= bucket_size {
+            FILL_BINS
+        } else {
+            (fill * FILL_BINS as u64 / bucket_size.max(1)) as usize
+        };
+        rows[bin].buckets += 1;
+    }
+    rows
+}
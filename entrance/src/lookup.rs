@@ -1,28 +1,96 @@
 // this file adds the operation to look up hashes based on a specified prefix
-use crate::{Record, OUTPUT_FOLDER};
-use bincode;
+use crate::hash_generator::{extract_prefix, generate_hash};
+use crate::prefix::Prefix;
+use crate::report::{self, OutputFormat};
+use crate::vault::{self, VaultFooter};
+use crate::{Record, HASH_SIZE, NONCE_SIZE, RECORD_SIZE};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::convert::TryInto;
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
 use std::time::Instant;
 
-const RECORD_SIZE: usize = 32; // 6 bytes for nonce + 26 bytes for hash
+const SCAN_CHUNK_RECORDS: u64 = 256; // records read per call while collecting matches
+const INTERPOLATION_WINDOW: u64 = 128; // records read around each interpolated guess (4 KiB, about one page)
+const MAX_INTERPOLATION_PROBES: usize = 4; // guesses before falling back to binary search
 
-pub fn lookup_by_prefix(filename: &str, prefix: &str) -> io::Result<()> {
-    let path = PathBuf::from(OUTPUT_FOLDER).join(filename);
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
+// how the first match is located inside a sorted bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchStrategy {
+    Binary,        // halves the range with one single-record read per step
+    Interpolation, // guesses the position from the hash value, since BLAKE3 output is uniform
+}
+
+impl SearchStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "binary" => Some(SearchStrategy::Binary),
+            "interpolation" => Some(SearchStrategy::Interpolation),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SearchStrategy::Binary => "binary",
+            SearchStrategy::Interpolation => "interpolation",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LookupSummary {
+    vault: String,
+    prefix_hex: String,
+    prefix_bits: usize,
+    strategy: &'static str,
+    matches: usize,
+    seeks: usize,
+    duration_us: f64,
+}
 
-    // println!("{:?}", reader.capacity());
+#[derive(Serialize, Default)]
+struct LookupRecord {
+    nonce: u64,
+    hash_hex: String,
+}
 
-    let total_size = reader.seek(SeekFrom::End(0))? as usize;
-    // println!("Total size: {}", total_size);
-    let num_records = total_size / RECORD_SIZE;
+pub fn lookup_by_prefix(
+    path: &Path,
+    prefix: &Prefix,
+    strategy: SearchStrategy,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let (file, footer) = open_sorted_vault(path)?;
 
     let start_time = Instant::now();
-    let (records, seek_count) = binary_search_by_prefix(&mut reader, num_records, prefix)?;
+    let (records, seek_count) = find_by_prefix(&file, &footer, prefix, strategy)?;
     let duration = start_time.elapsed();
 
+    if !format.is_table() {
+        let summary = LookupSummary {
+            vault: path.display().to_string(),
+            prefix_hex: prefix.hex(),
+            prefix_bits: prefix.bits(),
+            strategy: strategy.name(),
+            matches: records.len(),
+            seeks: seek_count,
+            duration_us: duration.as_secs_f64() * 1e6,
+        };
+        let rows = records
+            .iter()
+            .map(|record| LookupRecord {
+                nonce: nonce_to_decimal(&record.nonce),
+                hash_hex: hash_to_string(&record.hash),
+            })
+            .collect::<Vec<LookupRecord>>();
+        return report::emit_rows(format, "lookup", &summary, "records", &rows);
+    }
+
     if !records.is_empty() {
         println!("{:<16} | {:<64}", "Nonce (Decimal)", "Hash (Hex)");
         println!("{}", "-".repeat(88));
@@ -32,115 +100,665 @@ pub fn lookup_by_prefix(filename: &str, prefix: &str) -> io::Result<()> {
             println!("{:<16} | {}", nonce_decimal, hash_hex);
         }
     } else {
-        println!("No records found with the specified prefix '{}'", prefix);
+        println!("No records found with the specified prefix {}", prefix);
     }
 
     println!(
-        "Search duration: {:?}, Seek operations: {}",
-        duration, seek_count
+        "Search duration: {:?}, Seek operations: {} ({} search)",
+        duration,
+        seek_count,
+        strategy.name()
     );
     Ok(())
 }
 
-fn binary_search_by_prefix<R: Read + Seek>(
-    reader: &mut R,
-    num_records: usize,
-    prefix: &str,
+// lookups rely on the order inside each bucket, so unsorted vaults are refused
+pub fn open_sorted_vault(path: &Path) -> io::Result<(File, VaultFooter)> {
+    let (file, footer) = vault::open_vault(path)?;
+    if !footer.sorted {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} was finalized without sorting, lookups would be wrong",
+                path.display()
+            ),
+        ));
+    }
+    Ok((file, footer))
+}
+
+// every record whose hash starts with `prefix`, in vault order, plus the number of seeks it took
+pub fn find_by_prefix(
+    file: &File,
+    footer: &VaultFooter,
+    prefix: &Prefix,
+    strategy: SearchStrategy,
+) -> io::Result<(Vec<Record>, usize)> {
+    find_by_prefix_limited(file, footer, prefix, strategy, usize::MAX)
+}
+
+// like `find_by_prefix`, but stops once more than `limit` records matched, so a short prefix can't pull a
+// whole vault into memory; getting `limit + 1` records back means the prefix matches too many
+pub fn find_by_prefix_limited(
+    file: &File,
+    footer: &VaultFooter,
+    prefix: &Prefix,
+    strategy: SearchStrategy,
+    limit: usize,
 ) -> io::Result<(Vec<Record>, usize)> {
-    let mut low = 0;
-    let mut high = num_records as isize - 1;
     let mut records = Vec::new();
     let mut seek_count = 0;
 
-    while low <= high {
-        let mid = (low + high) / 2;
-        reader.seek(SeekFrom::Start((mid as usize * RECORD_SIZE) as u64))?;
-        seek_count += 1;
+    // the prefix pins down the bucket, or a run of neighbouring buckets if it is shorter than the bucket prefix
+    let bucket_size = footer.bucket_size.max(1);
+    let num_buckets = footer.num_records.div_ceil(bucket_size);
+    let prefix_size = footer.prefix_size as usize;
+    let (first_bucket, last_bucket) = if prefix.bits() >= prefix_size {
+        let bucket = extract_prefix(prefix.bytes(), prefix_size);
+        (bucket, bucket)
+    } else {
+        let free_bits = prefix_size - prefix.bits();
+        let bucket = extract_prefix(prefix.bytes(), prefix.bits()) << free_bits;
+        (bucket, bucket + (1u64 << free_bits) - 1)
+    };
 
-        if let Some(record) = deserialize_next_record(reader)? {
-            let hash_hex = hash_to_string(&record.hash);
-            if hash_hex.starts_with(prefix) {
-                records.push(record);
-                collect_records(
-                    reader,
-                    mid + 1,
-                    num_records,
+    for bucket in first_bucket..=last_bucket.min(num_buckets.saturating_sub(1)) {
+        let start = bucket * bucket_size;
+        let end = (start + bucket_size).min(footer.num_records);
+        let first = match strategy {
+            SearchStrategy::Binary => lower_bound(file, start, end, prefix, &mut seek_count)?,
+            SearchStrategy::Interpolation => {
+                // every hash of the bucket starts with the bucket's bits, which bounds its key
+                let shift = 64 - prefix_size as u32;
+                let low_key = (bucket as u128) << shift;
+                let high_key = (bucket as u128 + 1) << shift;
+                interpolation_lower_bound(
+                    file,
+                    start,
+                    end,
                     prefix,
-                    true,
-                    &mut records,
+                    (low_key, high_key),
                     &mut seek_count,
-                )?;
-                collect_records(
-                    reader,
-                    mid - 1,
-                    num_records,
-                    prefix,
-                    false,
-                    &mut records,
-                    &mut seek_count,
-                )?;
-                break;
-            } else if hash_hex < prefix.to_owned() {
-                low = mid + 1;
-            } else {
-                high = mid - 1;
+                )?
             }
+        };
+        collect_matches(
+            file,
+            first,
+            end,
+            prefix,
+            limit,
+            &mut records,
+            &mut seek_count,
+        )?;
+        if records.len() > limit {
+            break;
         }
     }
 
     Ok((records, seek_count))
 }
 
-fn collect_records<R: Read + Seek>(
-    reader: &mut R,
-    start: isize,
-    end: usize,
-    prefix: &str,
-    forward: bool,
+// one seek to the first match, then a sequential scan over the rest of [first, end), stopping past `limit` records
+fn collect_matches(
+    file: &File,
+    first: u64,
+    end: u64,
+    prefix: &Prefix,
+    limit: usize,
     records: &mut Vec<Record>,
     seek_count: &mut usize,
 ) -> io::Result<()> {
+    let mut current = first;
+    if current < end {
+        *seek_count += 1;
+    }
+    while current < end {
+        let count = SCAN_CHUNK_RECORDS.min(end - current);
+        let buffer = read_records(file, current, count)?;
+        for record in buffer.chunks_exact(RECORD_SIZE) {
+            if compare_record(record, prefix) != Ordering::Equal || records.len() > limit {
+                return Ok(());
+            }
+            records.push(Record {
+                nonce: record[..NONCE_SIZE].try_into().unwrap(),
+                hash: record[NONCE_SIZE..].try_into().unwrap(),
+            });
+        }
+        current += count;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct NonceLookupSummary {
+    vault: String,
+    nonce: u64,
+    bucket: u64,
+    expected_hash_hex: String,
+    present: bool,
+    record_index: Option<u64>,
+    offset_bytes: Option<u64>,
+    stored_hash_hex: Option<String>,
+    hash_matches: bool,
+    seeks: usize,
+    duration_us: f64,
+}
+
+// where a nonce was found, and whether the hash stored next to it is the one BLAKE3 gives
+pub struct NonceLookup {
+    pub nonce: u64,
+    pub bucket: u64,
+    pub expected_hash: [u8; HASH_SIZE],
+    pub found: Option<(u64, [u8; HASH_SIZE])>, // record index and stored hash
+    pub seek_count: usize,
+}
+
+impl NonceLookup {
+    pub fn hash_matches(&self) -> bool {
+        self.found
+            .is_some_and(|(_, stored_hash)| stored_hash == self.expected_hash)
+    }
+
+    pub fn print(&self) {
+        println!("Nonce: {}", self.nonce);
+        println!("Expected hash: {}", hash_to_string(&self.expected_hash));
+        println!("Bucket: {}", self.bucket);
+        match self.found {
+            Some((index, stored_hash)) => {
+                println!(
+                    "Present: yes, record {} (byte offset {})",
+                    index,
+                    index * RECORD_SIZE as u64
+                );
+                println!("Stored hash: {}", hash_to_string(&stored_hash));
+                println!(
+                    "Stored hash matches: {}",
+                    if self.hash_matches() { "yes" } else { "no" }
+                );
+            }
+            None => println!("Present: no"),
+        }
+    }
+}
+
+pub fn lookup_by_nonce(path: &Path, nonce: u64, format: OutputFormat) -> io::Result<()> {
+    let (file, footer) = vault::open_vault(path)?;
+
+    let start_time = Instant::now();
+    let result = find_by_nonce(&file, &footer, nonce)?;
+    let duration = start_time.elapsed();
+
+    if !format.is_table() {
+        let summary = NonceLookupSummary {
+            vault: path.display().to_string(),
+            nonce: result.nonce,
+            bucket: result.bucket,
+            expected_hash_hex: hash_to_string(&result.expected_hash),
+            present: result.found.is_some(),
+            record_index: result.found.map(|(index, _)| index),
+            offset_bytes: result.found.map(|(index, _)| index * RECORD_SIZE as u64),
+            stored_hash_hex: result.found.map(|(_, hash)| hash_to_string(&hash)),
+            hash_matches: result.hash_matches(),
+            seeks: result.seek_count,
+            duration_us: duration.as_secs_f64() * 1e6,
+        };
+        return report::emit_object(format, "nonce_lookup", &summary);
+    }
+
+    result.print();
+    println!(
+        "Search duration: {:?}, Seek operations: {}",
+        duration, result.seek_count
+    );
+    Ok(())
+}
+
+// recomputes the hash of `nonce`, goes straight to its bucket and looks for the record there
+pub fn find_by_nonce(file: &File, footer: &VaultFooter, nonce: u64) -> io::Result<NonceLookup> {
+    if nonce >> (NONCE_SIZE * 8) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("nonce {} does not fit in {} bytes", nonce, NONCE_SIZE),
+        ));
+    }
+
+    let (bucket, expected) = generate_hash(nonce, footer.prefix_size as usize);
+    let mut result = NonceLookup {
+        nonce,
+        bucket,
+        expected_hash: expected.hash,
+        found: None,
+        seek_count: 0,
+    };
+
+    let bucket_size = footer.bucket_size.max(1);
+    let start = bucket * bucket_size;
+    if start >= footer.num_records {
+        return Ok(result);
+    }
+    let end = (start + bucket_size).min(footer.num_records);
+
+    // in a sorted bucket the record sits where its hash belongs
+    if footer.sorted {
+        let hash = Prefix::from_bytes(&expected.hash, HASH_SIZE * 8);
+        let index = lower_bound(file, start, end, &hash, &mut result.seek_count)?;
+        if index < end {
+            let record = read_records(file, index, 1)?;
+            result.seek_count += 1;
+            if record[..NONCE_SIZE] == expected.nonce {
+                result.found = Some((index, record[NONCE_SIZE..].try_into().unwrap()));
+                return Ok(result);
+            }
+        }
+    }
+
+    // unsorted, or the stored hash is damaged and the record is out of place: scan the whole bucket
+    result.seek_count += 1;
     let mut current = start;
-    while (forward && current < end as isize) || (!forward && current >= 0) {
-        reader.seek(SeekFrom::Start((current as usize * RECORD_SIZE) as u64))?;
+    while current < end {
+        let count = SCAN_CHUNK_RECORDS.min(end - current);
+        let buffer = read_records(file, current, count)?;
+        if let Some(position) = buffer.chunks_exact(RECORD_SIZE).position(|record| {
+            record[..NONCE_SIZE] == expected.nonce && record.iter().any(|&b| b != 0)
+        }) {
+            let record = &buffer[position * RECORD_SIZE..(position + 1) * RECORD_SIZE];
+            result.found = Some((
+                current + position as u64,
+                record[NONCE_SIZE..].try_into().unwrap(),
+            ));
+            break;
+        }
+        current += count;
+    }
+    Ok(result)
+}
+
+// the record range of every bucket, without its trailing zero padding; built once, so a long-running
+// server doesn't have to look for the padding again on every query
+pub struct BucketIndex {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl BucketIndex {
+    pub fn build(file: &File, footer: &VaultFooter) -> io::Result<Self> {
+        let num_buckets = footer.num_records.div_ceil(footer.bucket_size.max(1));
+        let ranges = (0..num_buckets)
+            .into_par_iter()
+            .map(|bucket| bucket_range(file, footer, bucket, &mut 0))
+            .collect::<io::Result<Vec<(u64, u64)>>>()?;
+        Ok(Self { ranges })
+    }
+
+    pub fn range(&self, bucket: u64) -> (u64, u64) {
+        self.ranges[bucket as usize]
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+// the records of a bucket, without the zero padding at its end
+pub fn bucket_range(
+    file: &File,
+    footer: &VaultFooter,
+    bucket: u64,
+    seek_count: &mut usize,
+) -> io::Result<(u64, u64)> {
+    let bucket_size = footer.bucket_size.max(1);
+    let start = bucket * bucket_size;
+    let end = (start + bucket_size).min(footer.num_records);
+    if start == end {
+        return Ok((start, end));
+    }
+
+    // most buckets are full, so check the last record before searching for where the padding starts
+    *seek_count += 1;
+    if !is_padding(&read_records(file, end - 1, 1)?) {
+        return Ok((start, end));
+    }
+    let after_every_hash = Prefix::from_bytes(&[0xff; HASH_SIZE], HASH_SIZE * 8);
+    let real_end = lower_bound(file, start, end, &after_every_hash, seek_count)?;
+    Ok((start, real_end))
+}
+
+// index of the first record in [start, end) whose hash is not below `prefix`
+pub fn lower_bound(
+    file: &File,
+    start: u64,
+    end: u64,
+    prefix: &Prefix,
+    seek_count: &mut usize,
+) -> io::Result<u64> {
+    let mut low = start;
+    let mut high = end;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let record = read_records(file, mid, 1)?;
         *seek_count += 1;
-        if let Some(record) = deserialize_next_record(reader)? {
-            let hash_hex = hash_to_string(&record.hash);
-            if hash_hex.starts_with(prefix) {
-                if forward {
-                    records.push(record);
-                } else {
-                    records.insert(0, record);
+        if compare_record(&record, prefix) == Ordering::Less {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+// same answer as `lower_bound`, but each step reads a window around the position the key predicts, given that
+// the keys (leading 64 bits) of the records in [start, end) lie in [low_key, high_key) and are spread uniformly;
+// after a few guesses that miss, the rest of the range is binary searched
+pub fn interpolation_lower_bound(
+    file: &File,
+    start: u64,
+    end: u64,
+    prefix: &Prefix,
+    (mut low_key, mut high_key): (u128, u128),
+    seek_count: &mut usize,
+) -> io::Result<u64> {
+    let key = hash_key(prefix.bytes());
+    let mut low = start;
+    let mut high = end;
+    for _ in 0..MAX_INTERPOLATION_PROBES {
+        if high - low <= INTERPOLATION_WINDOW {
+            break;
+        }
+        let offset =
+            key.saturating_sub(low_key) * (high - low) as u128 / (high_key - low_key).max(1);
+        let guess = low + (offset as u64).min(high - low - 1);
+        let window_start = guess
+            .saturating_sub(INTERPOLATION_WINDOW / 2)
+            .clamp(low, high - INTERPOLATION_WINDOW);
+        let window = read_records(file, window_start, INTERPOLATION_WINDOW)?;
+        *seek_count += 1;
+
+        let first = window
+            .chunks_exact(RECORD_SIZE)
+            .position(|record| compare_record(record, prefix) != Ordering::Less);
+        match first {
+            // the whole window is at or past the key, so the answer is at or before its start
+            Some(0) => {
+                high = window_start;
+                if !is_padding(&window[..RECORD_SIZE]) {
+                    high_key = hash_key(&window[NONCE_SIZE..RECORD_SIZE]) + 1;
                 }
-            } else {
-                break;
+            }
+            Some(i) => return Ok(window_start + i as u64),
+            // the whole window is below the key
+            None => {
+                low = window_start + INTERPOLATION_WINDOW;
+                low_key = hash_key(&window[window.len() - HASH_SIZE..]);
             }
         }
-        current += if forward { 1 } else { -1 };
     }
-    Ok(())
+
+    // a range that fits in one window is read in one go, anything larger is binary searched
+    if high - low <= INTERPOLATION_WINDOW {
+        if low == high {
+            return Ok(low);
+        }
+        let records = read_records(file, low, high - low)?;
+        *seek_count += 1;
+        let first = records
+            .chunks_exact(RECORD_SIZE)
+            .position(|record| compare_record(record, prefix) != Ordering::Less);
+        return Ok(first.map_or(high, |i| low + i as u64));
+    }
+    lower_bound(file, low, high, prefix, seek_count)
+}
+
+// the leading 64 bits of a hash, which is all the resolution interpolation needs
+fn hash_key(hash: &[u8]) -> u128 {
+    u64::from_be_bytes(hash[..8].try_into().unwrap()) as u128
+}
+
+// zero padding sits at the end of a short bucket, so it sorts after every real hash
+pub fn compare_record(record: &[u8], prefix: &Prefix) -> Ordering {
+    if is_padding(record) {
+        return Ordering::Greater;
+    }
+    prefix.compare(&record[NONCE_SIZE..])
+}
+
+pub fn is_padding(record: &[u8]) -> bool {
+    record.iter().all(|&byte| byte == 0)
+}
+
+pub fn read_records(file: &File, first_record: u64, count: u64) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; count as usize * RECORD_SIZE];
+    file.read_exact_at(&mut buffer, first_record * RECORD_SIZE as u64)?;
+    Ok(buffer)
 }
 
-fn nonce_to_decimal(nonce: &[u8; 6]) -> u64 {
+pub fn nonce_to_decimal(nonce: &[u8; NONCE_SIZE]) -> u64 {
     nonce.iter().fold(0u64, |acc, &b| acc * 256 + b as u64)
 }
 
-fn hash_to_string(hash: &[u8; 26]) -> String {
+pub fn hash_to_string(hash: &[u8; HASH_SIZE]) -> String {
     hash.iter()
         .map(|b| format!("{:02x}", b))
         .collect::<Vec<String>>()
         .join("")
 }
 
-fn deserialize_next_record<R: Read>(reader: &mut R) -> io::Result<Option<Record>> {
-    let mut buffer = vec![0u8; RECORD_SIZE];
-    match reader.read_exact(&mut buffer) {
-        Ok(_) => {
-            let record: Record = bincode::deserialize(&buffer)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            Ok(Some(record))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    const PREFIX_SIZE: u32 = 4;
+    const NUM_BUCKETS: usize = 1 << PREFIX_SIZE;
+    const BUCKET_SIZE: usize = 400; // a few interpolation windows
+                                    // records per bucket, the rest is padding: full first and last buckets, empty ones, and every size in between
+    const FILLED: [usize; NUM_BUCKETS] = [
+        400, 0, 1, 2, 127, 128, 129, 250, 0, 399, 300, 64, 3, 200, 399, 400,
+    ];
+
+    // a hand-built sorted vault whose buckets are filled to `FILLED`, removed again when the test is done
+    struct TestVault {
+        path: PathBuf,
+        file: File,
+        footer: VaultFooter,
+        buckets: Vec<Vec<[u8; RECORD_SIZE]>>,
+    }
+
+    impl TestVault {
+        fn new(name: &str) -> Self {
+            let mut buckets = vec![Vec::new(); NUM_BUCKETS];
+            let mut nonce = 0;
+            while buckets
+                .iter()
+                .zip(FILLED)
+                .any(|(bucket, filled)| bucket.len() < filled)
+            {
+                let (bucket, record) = generate_hash(nonce, PREFIX_SIZE as usize);
+                let (bucket, filled) = (&mut buckets[bucket as usize], FILLED[bucket as usize]);
+                if bucket.len() < filled {
+                    let mut bytes = [0u8; RECORD_SIZE];
+                    bytes[..NONCE_SIZE].copy_from_slice(&record.nonce);
+                    bytes[NONCE_SIZE..].copy_from_slice(&record.hash);
+                    bucket.push(bytes);
+                }
+                nonce += 1;
+            }
+            for bucket in buckets.iter_mut() {
+                bucket.sort_unstable_by(|a, b| a[NONCE_SIZE..].cmp(&b[NONCE_SIZE..]));
+                bucket.resize(BUCKET_SIZE, [0u8; RECORD_SIZE]);
+            }
+
+            let path = std::env::temp_dir().join(format!(
+                "vault76-lookup-test-{}-{}.bin",
+                name,
+                std::process::id()
+            ));
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            file.write_all(&buckets.concat().concat()).unwrap();
+            let footer = VaultFooter::new(
+                20,
+                PREFIX_SIZE,
+                true,
+                NUM_BUCKETS,
+                BUCKET_SIZE,
+                NUM_BUCKETS * BUCKET_SIZE,
+                0x5eed,
+            );
+            Self {
+                path,
+                file,
+                footer,
+                buckets,
+            }
+        }
+
+        // what a plain scan finds: where `prefix` would go in `bucket`, and every record it matches, in vault order
+        fn scan(&self, bucket: usize, prefix: &Prefix) -> (u64, Vec<u64>) {
+            let records = &self.buckets[bucket];
+            let first = records
+                .iter()
+                .position(|record| compare_record(record, prefix) != Ordering::Less)
+                .unwrap_or(BUCKET_SIZE);
+            let matches = records
+                .iter()
+                .filter(|record| compare_record(&record[..], prefix) == Ordering::Equal)
+                .map(|record| nonce_to_decimal(record[..NONCE_SIZE].try_into().unwrap()))
+                .collect();
+            ((bucket * BUCKET_SIZE + first) as u64, matches)
+        }
+    }
+
+    impl Drop for TestVault {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    // prefixes that land in `bucket`: the hashes it holds at several lengths, and hashes one bit off them that
+    // match nothing, plus the lowest and highest possible prefix of the bucket
+    fn prefixes_in(vault: &TestVault, bucket: usize) -> Vec<Prefix> {
+        let mut prefixes = Vec::new();
+        let lowest = [(bucket as u8) << 4; HASH_SIZE];
+        let mut highest = [0xff; HASH_SIZE];
+        highest[0] = ((bucket as u8) << 4) | 0x0f;
+        for bits in [PREFIX_SIZE as usize, 8, 13, 20, 64, HASH_SIZE * 8] {
+            prefixes.push(Prefix::from_bytes(&lowest, bits));
+            prefixes.push(Prefix::from_bytes(&highest, bits));
+            for record in vault.buckets[bucket].iter().step_by(37) {
+                let hash = &record[NONCE_SIZE..];
+                if is_padding(record) || bits <= PREFIX_SIZE as usize {
+                    continue;
+                }
+                prefixes.push(Prefix::from_bytes(hash, bits));
+                let mut off_by_one = [0u8; HASH_SIZE];
+                off_by_one.copy_from_slice(hash);
+                off_by_one[(bits - 1) / 8] ^= 0x80 >> ((bits - 1) % 8);
+                prefixes.push(Prefix::from_bytes(&off_by_one, bits));
+            }
+        }
+        prefixes
+    }
+
+    #[test]
+    fn the_bucket_index_ends_every_bucket_before_its_padding() {
+        let vault = TestVault::new("index");
+        let index = BucketIndex::build(&vault.file, &vault.footer).unwrap();
+        assert_eq!(index.num_buckets(), NUM_BUCKETS);
+        for (bucket, filled) in FILLED.iter().enumerate() {
+            let start = (bucket * BUCKET_SIZE) as u64;
+            let expected = (start, start + *filled as u64);
+            assert_eq!(index.range(bucket as u64), expected, "bucket {}", bucket);
+            assert_eq!(
+                bucket_range(&vault.file, &vault.footer, bucket as u64, &mut 0).unwrap(),
+                expected,
+                "bucket {}",
+                bucket
+            );
+        }
+    }
+
+    #[test]
+    fn every_search_finds_the_same_first_record() {
+        let vault = TestVault::new("lower-bound");
+        let index = BucketIndex::build(&vault.file, &vault.footer).unwrap();
+        let shift = 64 - PREFIX_SIZE;
+        for bucket in 0..NUM_BUCKETS {
+            let start = (bucket * BUCKET_SIZE) as u64;
+            let end = start + BUCKET_SIZE as u64;
+            let (_, filled_end) = index.range(bucket as u64);
+            let keys = ((bucket as u128) << shift, (bucket as u128 + 1) << shift);
+            for prefix in prefixes_in(&vault, bucket) {
+                let (expected, _) = vault.scan(bucket, &prefix);
+                let binary = lower_bound(&vault.file, start, end, &prefix, &mut 0).unwrap();
+                let interpolated =
+                    interpolation_lower_bound(&vault.file, start, end, &prefix, keys, &mut 0)
+                        .unwrap();
+                // the padding sorts after every hash, so leaving it out of the range changes nothing
+                let indexed = lower_bound(&vault.file, start, filled_end, &prefix, &mut 0).unwrap();
+                let indexed_interpolated = interpolation_lower_bound(
+                    &vault.file,
+                    start,
+                    filled_end,
+                    &prefix,
+                    keys,
+                    &mut 0,
+                )
+                .unwrap();
+                assert_eq!(binary, expected, "bucket {} prefix {}", bucket, prefix);
+                assert_eq!(
+                    interpolated, expected,
+                    "bucket {} prefix {}",
+                    bucket, prefix
+                );
+                assert_eq!(indexed, expected, "bucket {} prefix {}", bucket, prefix);
+                assert_eq!(
+                    indexed_interpolated, expected,
+                    "bucket {} prefix {}",
+                    bucket, prefix
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn both_strategies_find_every_match_of_short_and_long_prefixes() {
+        let vault = TestVault::new("find");
+        let mut prefixes: Vec<Prefix> = (0..NUM_BUCKETS)
+            .flat_map(|bucket| prefixes_in(&vault, bucket))
+            .collect();
+        // shorter than the bucket prefix, so they span a run of buckets, including the first and the last one
+        for bits in 1..PREFIX_SIZE as usize {
+            prefixes.push(Prefix::from_bytes(&[0x00; HASH_SIZE], bits));
+            prefixes.push(Prefix::from_bytes(&[0xff; HASH_SIZE], bits));
+            prefixes.push(Prefix::from_bytes(&[0x90; HASH_SIZE], bits));
+        }
+
+        for prefix in prefixes {
+            let expected: Vec<u64> = (0..NUM_BUCKETS)
+                .filter(|&bucket| {
+                    // the buckets whose own prefix agrees with every bit the two have in common
+                    let bits = prefix.bits().min(PREFIX_SIZE as usize);
+                    let bucket_prefix = Prefix::from_bytes(&[(bucket as u8) << 4], bits);
+                    bucket_prefix.compare(prefix.bytes()) == Ordering::Equal
+                })
+                .flat_map(|bucket| vault.scan(bucket, &prefix).1)
+                .collect();
+            for strategy in [SearchStrategy::Binary, SearchStrategy::Interpolation] {
+                let (records, _) =
+                    find_by_prefix(&vault.file, &vault.footer, &prefix, strategy).unwrap();
+                let found: Vec<u64> = records
+                    .iter()
+                    .map(|record| nonce_to_decimal(&record.nonce))
+                    .collect();
+                assert_eq!(found, expected, "{} with {}", prefix, strategy.name());
+            }
         }
-        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
-        Err(e) => Err(e),
     }
 }
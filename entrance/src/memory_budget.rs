@@ -0,0 +1,63 @@
+// this file keeps the sort phase within --memory_limit by making each bucket wait for its share of memory
+use std::fs;
+use std::sync::{Condvar, Mutex};
+
+pub struct MemoryBudget {
+    total: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+// memory handed out by the budget; it is given back as soon as the permit is dropped
+pub struct MemoryPermit<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            available: Mutex::new(total),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    // what each of `holders` gets when they all hold memory at the same time
+    pub fn share(&self, holders: usize) -> usize {
+        self.total / holders.max(1)
+    }
+
+    // blocks until `bytes` are free; requests larger than the whole budget are capped to it
+    pub fn acquire(&self, bytes: usize) -> MemoryPermit<'_> {
+        let bytes = bytes.min(self.total);
+        let mut available = self.available.lock().unwrap();
+        while *available < bytes {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= bytes;
+        MemoryPermit {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+impl Drop for MemoryPermit<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+// highest resident set size of this process so far, read from /proc (Linux only)
+pub fn peak_rss_bytes() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kilobytes * 1024)
+}
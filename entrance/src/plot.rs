@@ -0,0 +1,655 @@
+// this file runs the generation pipeline: it follows the bucket plan, generates and sorts the hashes, then finalizes,
+// copies and reports every vault of a run
+use crate::hash_sorter::{self, SortAlgorithm};
+use crate::memory_budget::{self, MemoryBudget};
+use crate::plot_plan::PlotPlan;
+use crate::progress_tracker::ProgressTracker;
+use crate::report::{self, OutputFormat};
+use crate::{hash_generator, plot_mover, store_hashes, vault, Record, RECORD_SIZE};
+use dashmap::DashMap;
+use rand::random;
+use rayon::prelude::*;
+use serde::Serialize;
+use spdlog::prelude::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// everything needed to create one or more vaults
+#[derive(Debug, Clone)]
+pub struct PlotOptions {
+    pub k: u32,
+    pub memory_limit: usize,      // in MB, shared by generation and sorting
+    pub file_size: Option<usize>, // in bytes (default: 2^k records)
+    pub num_threads: usize,
+    pub sorting: bool,
+    pub merge_sort: bool,
+    pub sort_algorithm: SortAlgorithm,
+    pub num_plots: usize,
+    pub temp_dir: PathBuf,          // where vaults are generated and sorted
+    pub final_dir: Option<PathBuf>, // where finished vaults are moved to
+    pub background_copy: bool,
+    pub output: Option<PathBuf>, // exact path of a single vault, instead of a unique name in temp_dir
+    pub debug: bool,
+}
+
+impl PlotOptions {
+    // the bucket layout these options lead to, or why there is none
+    pub fn plan(&self) -> io::Result<PlotPlan> {
+        PlotPlan::new(self.k, self.file_size, self.memory_limit, self.num_threads)
+    }
+}
+
+// what one finished vault of a run took, phase by phase (in seconds)
+#[derive(Debug, Clone)]
+pub struct PlottedVault {
+    pub path: PathBuf,
+    pub size_bytes: u64,   // the finished file, footer included
+    pub generation_s: f64, // generating and writing
+    pub sorting_s: f64,
+    pub sync_s: f64,
+    pub copy_s: Option<f64>, // only with a final directory
+}
+
+pub struct PlotRun {
+    pub k: u32,
+    pub num_records: usize, // per vault, after the planner rounded the file size
+    pub memory_limit: usize, // in bytes
+    pub vaults: Vec<PlottedVault>,
+}
+
+// one line of the run report
+#[derive(Serialize, Default)]
+struct PlotReport {
+    plot: usize,
+    vault: String,
+    k: u32,
+    num_records: usize,
+    size_bytes: u64,
+    generation_s: f64,
+    sorting_s: f64,
+    sync_s: f64,
+    copy_s: Option<f64>, // only with --final-dir
+}
+
+#[derive(Serialize)]
+struct RunSummary {
+    num_plots: usize,
+    memory_limit_bytes: usize,
+    peak_rss_bytes: Option<usize>,
+}
+
+pub fn create_plots(options: &PlotOptions) -> io::Result<PlotRun> {
+    let k = options.k;
+    let num_threads = options.num_threads;
+    let debug = options.debug;
+    let sorting_on = options.sorting;
+    let merge_sort = options.merge_sort;
+    let sort_algorithm = options.sort_algorithm;
+    let num_plots = options.num_plots;
+    let output_path = options.output.clone();
+    let temp_dir = options.temp_dir.clone();
+    let final_dir = options.final_dir.clone();
+    let background_copy = options.background_copy;
+
+    if output_path.is_some() && num_plots != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--output names a single vault; use --temp-dir to create several plots",
+        ));
+    }
+
+    // nothing is created on disk unless a valid bucket layout exists
+    let plan = options.plan()?;
+    if debug {
+        println!("-----------------Found valid config------------------");
+        plan.print();
+        println!("Sort mode: {}", if merge_sort { "merge" } else { "full" });
+    }
+    let PlotPlan {
+        memory_size,
+        file_size,
+        num_records,
+        write_size,
+        bucket_size,
+        num_buckets,
+        prefix_size,
+        expected_total_flushes,
+        in_memory,
+        ..
+    } = plan;
+
+    // the sort phase gets the whole memory limit, shared between the buckets being sorted at once
+    let sort_budget = MemoryBudget::new(plan.memory_limit);
+
+    let vault_dir = match output_path {
+        Some(ref output_path) => output_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        None => temp_dir.clone(),
+    };
+    if !vault_dir.as_os_str().is_empty() {
+        fs::create_dir_all(&vault_dir)?;
+    }
+    if let Some(ref final_dir) = final_dir {
+        fs::create_dir_all(final_dir)?;
+    }
+
+    if debug {
+        info!("Opening Vault Entrance...");
+    }
+    // initialize tracker to track progress of vault operations
+    // tracker outputs progress every 2 seconds (you can reduce this, but more redundant output lines)
+
+    // Initialize tracker if debug is true
+    let tracker = if debug {
+        Some(ProgressTracker::new(
+            num_records as u64,
+            expected_total_flushes,
+            Duration::from_secs(2),
+        ))
+    } else {
+        None
+    };
+
+    let map: DashMap<usize, Vec<Record>> = DashMap::with_capacity(num_buckets);
+
+    let thread_memory_limit = if file_size < memory_size {
+        file_size / num_threads
+    } else {
+        memory_size / num_threads // in bytes
+    };
+
+    let mut vaults: Vec<PlottedVault> = Vec::with_capacity(num_plots);
+    let mut pending_copies = Vec::new();
+
+    for plot_index in 0..num_plots {
+        let plot_id: u64 = random();
+        let mut vault_path = match output_path {
+            Some(ref output_path) => output_path.clone(),
+            None => temp_dir.join(vault_file_name(k, plot_id)),
+        };
+
+        // everything is written under a partial name until the vault is finalized
+        let partial_path = vault::partial_path(&vault_path);
+        if partial_path.exists() {
+            fs::remove_file(&partial_path)?;
+        }
+        // whatever goes wrong from here on, no partial vault or scratch file is left behind
+        let mut partial_files = PartialFiles {
+            partial_path: partial_path.clone(),
+            num_buckets,
+            finalized: false,
+        };
+
+        if let Some(ref tracker) = tracker {
+            tracker.reset(expected_total_flushes);
+        }
+
+        let start_vault_timer = Instant::now();
+
+        let mut total_generated = 0;
+
+        // defining offset vector for the generation phase
+        let mut offsets = vec![0; num_buckets];
+        for i in 1..num_buckets {
+            offsets[i] = offsets[i - 1] + bucket_size * RECORD_SIZE;
+        }
+        let offsets_vector: RwLock<Vec<usize>> = RwLock::new(offsets);
+
+        // every record in a bucket shares its leading prefix_size bits, so whole bytes of those can be skipped
+        let common_bytes = prefix_size as usize / 8;
+
+        // in merge mode, the length of every sorted run written into each disk bucket
+        let mut run_lengths: Vec<Vec<usize>> = vec![Vec::new(); num_buckets];
+
+        // one round of hash generation, filling every memory bucket of the map
+        let generate_round = || -> io::Result<()> {
+            let round_records = AtomicUsize::new(0);
+            (0..num_threads).into_par_iter().try_for_each(|_thread_index| {
+                let mut local_size = 0;
+                let mut nonce: u64 = random();
+
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.set_stage("[HASHGEN]");
+                    }
+                }
+                while local_size < thread_memory_limit {
+                    let (prefix, record) =
+                        hash_generator::generate_hash(nonce, prefix_size as usize);
+
+                    nonce += 1;
+
+                    let mut records = map
+                        .entry(prefix as usize)
+                        .or_insert_with(|| Vec::with_capacity(write_size / RECORD_SIZE));
+
+                    if records.len() >= write_size / RECORD_SIZE {
+                        // with every memory bucket full, no thread can finish its share; the plan rules this out,
+                        // so getting here is a bug that would otherwise spin forever
+                        if round_records.load(Ordering::Relaxed) >= memory_size / RECORD_SIZE {
+                            return Err(io::Error::other(format!(
+                                "every memory bucket is full but a thread still has {} bytes to generate",
+                                thread_memory_limit - local_size
+                            )));
+                        }
+                        continue;
+                    }
+                    records.push(record);
+                    round_records.fetch_add(1, Ordering::Relaxed);
+                    local_size += RECORD_SIZE;
+                }
+                // completed a batch of records processed
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.update_records_processed((local_size / RECORD_SIZE) as u64);
+                    }
+                }
+                Ok(())
+            })
+        };
+
+        let mut sorting_duration = 0.0;
+        let mut sync_duration = 0.0;
+        let generation_writing_duration;
+
+        if in_memory {
+            // the whole vault fits in RAM: generate it, sort every bucket and write the final file once
+            let start_generation = Instant::now();
+            generate_round()?;
+            let generation_duration = start_generation.elapsed().as_secs_f64();
+
+            if sorting_on {
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.set_stage("[SORTING]");
+                    }
+                }
+                let start_sorting = Instant::now();
+                map.par_iter_mut().for_each(|mut entry| {
+                    hash_sorter::sort_records(entry.value_mut(), sort_algorithm, common_bytes)
+                });
+                sorting_duration = start_sorting.elapsed().as_secs_f64();
+            }
+
+            let start_writing = Instant::now();
+            store_hashes::write_vault_sequentially(&map, &partial_path, num_buckets, bucket_size)?;
+            map.clear();
+            generation_writing_duration =
+                generation_duration + start_writing.elapsed().as_secs_f64();
+
+            if sorting_on {
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.set_stage("[SYNCING]");
+                    }
+                }
+                sync_duration = sync_vault_file(&partial_path)?;
+            }
+        } else {
+            let start_generation_writing = Instant::now();
+
+            // generate hashes and write them to disk
+            while total_generated < file_size {
+                generate_round()?;
+
+                // each memory bucket becomes one sorted run inside its disk bucket
+                if merge_sort {
+                    map.par_iter_mut().for_each(|mut entry| {
+                        hash_sorter::sort_records(entry.value_mut(), sort_algorithm, common_bytes)
+                    });
+                    for entry in map.iter() {
+                        run_lengths[*entry.key()].push(entry.value().len());
+                    }
+                }
+
+                store_hashes::flush_to_disk(&map, &partial_path, &offsets_vector)?;
+                total_generated += thread_memory_limit * num_threads;
+
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        let flush_increment = map.len();
+                        tracker.increment_flushes(flush_increment);
+                    }
+                }
+                map.clear();
+            }
+
+            generation_writing_duration = start_generation_writing.elapsed().as_secs_f64();
+
+            if sorting_on {
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.set_stage("[SORTING]");
+                        tracker.set_expected_flushes(num_buckets);
+                    }
+                }
+
+                let start_sorting = Instant::now();
+
+                let records_per_bucket = (num_records / num_buckets) as u64;
+
+                // one shared handle for every bucket; reads and writes are positional
+                let vault_file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&partial_path)?;
+
+                if merge_sort {
+                    // merge into a second file, since the runs can't be merged in place while streaming
+                    let merged_path = merge_path(&partial_path);
+                    let merged_file = fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&merged_path)?;
+                    merged_file.set_len(file_size as u64)?;
+
+                    (0..num_buckets)
+                        .into_par_iter()
+                        .try_for_each(|bucket_index| {
+                            hash_sorter::merge_sorted_runs(
+                                &vault_file,
+                                &merged_file,
+                                bucket_index,
+                                bucket_size,
+                                &run_lengths[bucket_index],
+                                &sort_budget,
+                            )?;
+                            if debug {
+                                if let Some(ref tracker) = tracker {
+                                    tracker.update_records_processed(records_per_bucket);
+                                    tracker.increment_flushes(1);
+                                }
+                            }
+                            Ok::<(), io::Error>(())
+                        })?;
+
+                    fs::rename(&merged_path, &partial_path)?;
+                } else {
+                    // parallel processing of each bucket using rayon
+                    (0..num_buckets)
+                        .into_par_iter()
+                        .try_for_each(|bucket_index| {
+                            hash_sorter::sort_bucket(
+                                &vault_file,
+                                &scratch_path(&partial_path, bucket_index),
+                                bucket_index,
+                                bucket_size,
+                                sort_algorithm,
+                                common_bytes,
+                                &sort_budget,
+                            )?;
+                            if debug {
+                                if let Some(ref tracker) = tracker {
+                                    tracker.update_records_processed(records_per_bucket);
+                                    tracker.increment_flushes(1);
+                                }
+                            }
+                            Ok::<(), io::Error>(())
+                        })?;
+                }
+
+                sorting_duration = start_sorting.elapsed().as_secs_f64();
+
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.report_progress();
+                        tracker.set_stage("[SYNCING]");
+                    }
+                }
+                sync_duration = sync_vault_file(&partial_path)?;
+            }
+        }
+
+        // only now is the vault complete: stamp the footer and rename it into place
+        let footer = vault::VaultFooter::new(
+            k,
+            prefix_size,
+            sorting_on,
+            num_buckets,
+            bucket_size,
+            num_records,
+            plot_id,
+        );
+        vault::finalize_vault(&partial_path, &vault_path, &footer)?;
+        partial_files.finalized = true;
+        let vault_size = fs::metadata(&vault_path)?.len();
+
+        // stream the finished vault from the temp directory to the final directory
+        let mut copy_duration = None;
+        if let Some(ref final_dir) = final_dir {
+            if background_copy {
+                pending_copies.push((
+                    plot_index,
+                    plot_mover::spawn_move_to_final_dir(vault_path.clone(), final_dir.clone()),
+                ));
+            } else {
+                if debug {
+                    if let Some(ref tracker) = tracker {
+                        tracker.set_stage("[COPYING]");
+                    }
+                }
+                let (final_path, duration) = plot_mover::move_to_final_dir(&vault_path, final_dir)?;
+                vault_path = final_path;
+                copy_duration = Some(duration);
+            }
+        }
+
+        if debug {
+            let duration_in_seconds = start_vault_timer.elapsed().as_secs_f64();
+            let hashes_per_second = num_records as f64 / duration_in_seconds / 1_000_000.0; // convert to MH/s
+            let bytes_per_second = file_size as f64 / 1024.0 / 1024.0 / duration_in_seconds; // convert bytes to megabytes
+
+            println!(
+                "Completed {} GB vault [{}] in {:.2} seconds: {:.2} MH/s {:.2} MB/s",
+                file_size / 1024 / 1024 / 1024,
+                vault_path.display(),
+                duration_in_seconds,
+                hashes_per_second,
+                bytes_per_second
+            );
+        }
+
+        vaults.push(PlottedVault {
+            path: vault_path,
+            size_bytes: vault_size,
+            generation_s: generation_writing_duration,
+            sorting_s: sorting_duration,
+            sync_s: sync_duration,
+            copy_s: copy_duration,
+        });
+    }
+
+    // wait for any copies that were left running in the background
+    for (plot_index, handle) in pending_copies {
+        let (final_path, duration) = handle.join().map_err(|_| {
+            io::Error::other("the thread copying a vault to its final directory panicked")
+        })??;
+        vaults[plot_index].path = final_path;
+        vaults[plot_index].copy_s = Some(duration);
+    }
+
+    Ok(PlotRun {
+        k,
+        num_records,
+        memory_limit: sort_budget.total(),
+        vaults,
+    })
+}
+
+impl PlotRun {
+    // the timings of every plot: bare CSV lines in table mode (read by the benchmark scripts), otherwise a run report
+    pub fn report(&self, format: OutputFormat) -> io::Result<()> {
+        if format.is_table() {
+            // one line per plot; the copy column only appears when a final directory was given
+            for plotted in &self.vaults {
+                match plotted.copy_s {
+                    Some(copy) => println!(
+                        "{},{},{},{}",
+                        plotted.generation_s, plotted.sorting_s, plotted.sync_s, copy
+                    ),
+                    None => println!(
+                        "{},{},{}",
+                        plotted.generation_s, plotted.sorting_s, plotted.sync_s
+                    ),
+                }
+            }
+        } else {
+            let plots = self
+                .vaults
+                .iter()
+                .enumerate()
+                .map(|(plot, plotted)| PlotReport {
+                    plot,
+                    vault: plotted.path.display().to_string(),
+                    k: self.k,
+                    num_records: self.num_records,
+                    size_bytes: plotted.size_bytes,
+                    generation_s: plotted.generation_s,
+                    sorting_s: plotted.sorting_s,
+                    sync_s: plotted.sync_s,
+                    copy_s: plotted.copy_s,
+                })
+                .collect::<Vec<PlotReport>>();
+            let summary = RunSummary {
+                num_plots: plots.len(),
+                memory_limit_bytes: self.memory_limit,
+                peak_rss_bytes: memory_budget::peak_rss_bytes(),
+            };
+            report::emit_rows(format, "run", &summary, "plots", &plots)?;
+        }
+
+        // on stderr, so scripts reading the timing lines from stdout are not affected
+        match memory_budget::peak_rss_bytes() {
+            Some(peak_rss) => eprintln!(
+                "Peak RSS: {} bytes ({} MB), memory limit: {} MB",
+                peak_rss,
+                peak_rss / 1024 / 1024,
+                self.memory_limit / 1024 / 1024
+            ),
+            None => eprintln!("Peak RSS: unavailable on this platform"),
+        }
+        Ok(())
+    }
+}
+
+// sync the file and close it once done; returns how long the sync took (in seconds)
+fn sync_vault_file(path: &Path) -> io::Result<f64> {
+    let file = fs::OpenOptions::new().read(true).open(path)?;
+    let sync_timer = Instant::now();
+    file.sync_data()?;
+    Ok(sync_timer.elapsed().as_secs_f64())
+}
+
+// where the runs of every bucket are merged in merge mode, next to the partial vault
+fn merge_path(partial_path: &Path) -> PathBuf {
+    partial_path.with_extension("merging")
+}
+
+// where a bucket too big for the memory budget is merged during its external sort
+fn scratch_path(partial_path: &Path, bucket_index: usize) -> PathBuf {
+    partial_path.with_extension(format!("bucket{}", bucket_index))
+}
+
+// the files of a plot that is still being built; dropping it before the vault is finalized (an error or a panic)
+// removes them all, so a failed plot never leaves a partial vault or scratch file behind
+struct PartialFiles {
+    partial_path: PathBuf,
+    num_buckets: usize,
+    finalized: bool,
+}
+
+impl Drop for PartialFiles {
+    fn drop(&mut self) {
+        if self.finalized {
+            return;
+        }
+        let _ = fs::remove_file(&self.partial_path);
+        let _ = fs::remove_file(merge_path(&self.partial_path));
+        for bucket_index in 0..self.num_buckets {
+            let _ = fs::remove_file(scratch_path(&self.partial_path, bucket_index));
+        }
+    }
+}
+
+// every plot gets its own name, so one directory can hold many vaults
+fn vault_file_name(k: u32, plot_id: u64) -> String {
+    format!("vault76-k{}-{:016x}.bin", k, plot_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify;
+
+    // a scratch directory of its own for every test, removed again when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "vault76-plot-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn buckets_larger_than_a_threads_memory_share_are_sorted_externally() {
+        let dir = TestDir::new("spill");
+        let vault_path = dir.0.join("vault.bin");
+        let options = PlotOptions {
+            k: 19,
+            memory_limit: 1,
+            file_size: None,
+            num_threads: 256,
+            sorting: true,
+            merge_sort: false,
+            sort_algorithm: SortAlgorithm::Radix,
+            num_plots: 1,
+            temp_dir: dir.0.clone(),
+            final_dir: None,
+            background_copy: false,
+            output: Some(vault_path.clone()),
+            debug: false,
+        };
+        // no layout sorts 2^19 records in 1 MB with 256 threads, so every bucket spills to a scratch file
+        let plan = options.plan().unwrap();
+        assert!(plan.bucket_size * RECORD_SIZE > plan.memory_limit / plan.num_threads);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.num_threads)
+            .build()
+            .unwrap();
+        let run = pool.install(|| create_plots(&options)).unwrap();
+        assert_eq!(run.vaults.len(), 1);
+        assert_eq!(run.vaults[0].path, vault_path);
+
+        let report = verify::verify_vault(&vault_path, Some(1 << 19), 1024 * 1024, false).unwrap();
+        assert!(report.is_valid());
+
+        // the partial vault and every scratch file are gone
+        let leftovers: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != "vault.bin")
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+}
@@ -0,0 +1,118 @@
+// this file moves a finished vault from the temporary (fast) directory to the final (slow) directory
+use crate::vault;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+const COPY_BUFFER_SIZE: usize = 16 * 1024 * 1024; // large sequential chunks keep HDD heads streaming
+
+// moves the vault at `src` into `final_dir`; returns the new path and how long the move took (in seconds)
+pub fn move_to_final_dir(src: &Path, final_dir: &Path) -> io::Result<(PathBuf, f64)> {
+    let start = Instant::now();
+    let file_name = src.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "vault path has no file name")
+    })?;
+    let dst = final_dir.join(file_name);
+
+    // a rename is free when both directories live on the same filesystem; only a rename across filesystems falls
+    // back to a copy, any other failure (a missing directory, no permission, ...) would fail the copy just the same
+    match fs::rename(src, &dst) {
+        Ok(()) => {}
+        Err(error) if error.raw_os_error() == Some(libc::EXDEV) => {
+            // copy under the partial name first, so a half-copied vault is never mistaken for a finished one
+            let partial = vault::partial_path(&dst);
+            if let Err(error) = copy_sequentially(src, &partial) {
+                let _ = fs::remove_file(&partial);
+                return Err(error);
+            }
+            fs::rename(&partial, &dst)?;
+            fs::remove_file(src)?;
+        }
+        Err(error) => return Err(error),
+    }
+
+    Ok((dst, start.elapsed().as_secs_f64()))
+}
+
+// same as move_to_final_dir, but runs on its own thread so the next plot can start right away
+pub fn spawn_move_to_final_dir(
+    src: PathBuf,
+    final_dir: PathBuf,
+) -> JoinHandle<io::Result<(PathBuf, f64)>> {
+    thread::spawn(move || move_to_final_dir(&src, &final_dir))
+}
+
+// streams the sorted vault front to back, so the destination disk only ever sees sequential writes
+fn copy_sequentially(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut reader = File::open(src)?;
+    let mut writer = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)?;
+
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..bytes_read])?;
+    }
+
+    writer.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a scratch directory of its own for every test, removed again when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "vault76-mover-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn vaults_are_renamed_within_a_filesystem() {
+        let dir = TestDir::new("rename");
+        let src = dir.0.join("vault.bin");
+        let final_dir = dir.0.join("final");
+        fs::create_dir(&final_dir).unwrap();
+        fs::write(&src, b"records").unwrap();
+
+        let (dst, _) = move_to_final_dir(&src, &final_dir).unwrap();
+        assert_eq!(dst, final_dir.join("vault.bin"));
+        assert_eq!(fs::read(&dst).unwrap(), b"records");
+        assert!(!src.exists());
+    }
+
+    #[test]
+    fn a_failed_rename_is_not_retried_as_a_copy() {
+        let dir = TestDir::new("missing");
+        let src = dir.0.join("vault.bin");
+        fs::write(&src, b"records").unwrap();
+
+        let error = move_to_final_dir(&src, &dir.0.join("missing")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(fs::read(&src).unwrap(), b"records");
+    }
+}
@@ -0,0 +1,404 @@
+// this file plans the bucket layout of a vault before anything is generated: how many buckets, how big the memory
+// and disk buckets are, and how often each one is flushed, all within the memory limit
+use crate::report::{self, OutputFormat};
+use crate::{NONCE_SIZE, RECORD_SIZE};
+use serde::Serialize;
+use std::io;
+
+const MIN_BUCKETS: usize = 64;
+pub const MAX_K: u32 = NONCE_SIZE as u32 * 8; // every nonce of a plot has to fit in its 6 bytes
+
+// a bucket layout that fits the memory limit; every size is in bytes unless it says otherwise
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlotPlan {
+    pub k: u32,
+    pub num_threads: usize,
+    #[serde(rename = "memory_limit_bytes")]
+    pub memory_limit: usize, // the whole limit, which the sort phase shares between buckets
+    #[serde(rename = "memory_size_bytes")]
+    pub memory_size: usize, // memory buckets together
+    #[serde(rename = "file_size_bytes")]
+    pub file_size: usize, // records in the vault, padding included
+    pub num_records: usize,
+    #[serde(rename = "write_size_bytes")]
+    pub write_size: usize, // one memory bucket
+    pub flush_size: usize,  // flushes into every disk bucket
+    pub bucket_size: usize, // records per disk bucket
+    pub num_buckets: usize,
+    pub prefix_size: u32, // bits of hash that pick the bucket
+    pub expected_total_flushes: usize,
+    #[serde(rename = "sort_memory_bytes")]
+    pub sort_memory: usize, // one disk bucket per thread, sorted at once; above the limit, buckets are sorted externally
+    pub in_memory: bool, // the whole vault fits the limit, so it is never read back
+}
+
+impl PlotPlan {
+    // looks for the largest memory buckets that still give at least MIN_BUCKETS disk buckets whose sort fits in
+    // memory; if no layout sorts in memory, the one with the smallest disk buckets is used and the buckets that don't
+    // fit a thread's share of the limit are sorted externally; `file_size` defaults to 2^k records
+    pub fn new(
+        k: u32,
+        file_size: Option<usize>,
+        memory_limit_mb: usize,
+        num_threads: usize,
+    ) -> io::Result<Self> {
+        if k == 0 || k > MAX_K {
+            return Err(invalid(format!(
+                "k must be between 1 and {}, not {}",
+                MAX_K, k
+            )));
+        }
+        if num_threads == 0 {
+            return Err(invalid("at least one thread is needed".to_string()));
+        }
+        if memory_limit_mb == 0 {
+            return Err(invalid(
+                "the memory limit must be at least 1 MB".to_string(),
+            ));
+        }
+        let file_size = file_size.unwrap_or((1usize << k) * RECORD_SIZE);
+        if file_size < RECORD_SIZE {
+            return Err(invalid(format!(
+                "a file size of {} bytes holds no record of {} bytes",
+                file_size, RECORD_SIZE
+            )));
+        }
+
+        let memory_limit = memory_limit_mb * 1024 * 1024;
+        // generation never needs more memory than the vault is big
+        let memory_size = memory_limit.min(file_size);
+        let flush_size = file_size.div_ceil(memory_size);
+
+        // looking for optimal combination of prefix length, num of buckets, memory bucket size, and disk bucket size
+        let mut write_size = 1024 * 1024 / flush_size;
+        let mut spilling_plan = None;
+        while write_size > 0 {
+            let num_buckets = file_size / (write_size * 1024 * flush_size);
+            let prefix_size = (num_buckets as f64).log(2.0).ceil() as u32 + 1;
+            let num_buckets = 2usize.pow(prefix_size);
+            let sort_memory = file_size / num_buckets * num_threads;
+
+            if sort_memory <= memory_size && num_buckets >= MIN_BUCKETS {
+                return Self::with_buckets(
+                    k,
+                    num_threads,
+                    memory_limit,
+                    memory_size,
+                    file_size,
+                    flush_size,
+                    num_buckets,
+                );
+            }
+            // the smaller the disk buckets, the less of them has to be sorted externally, so the last layout wins
+            if num_buckets >= MIN_BUCKETS {
+                if let Ok(plan) = Self::with_buckets(
+                    k,
+                    num_threads,
+                    memory_limit,
+                    memory_size,
+                    file_size,
+                    flush_size,
+                    num_buckets,
+                ) {
+                    spilling_plan = Some(plan);
+                }
+            }
+            write_size /= 2;
+        }
+
+        spilling_plan.ok_or_else(|| {
+            invalid(format!(
+                "no bucket layout fits {} bytes of records in {} MB with {} threads: {} buckets or more would leave \
+                 the threads no whole record to generate; raise the memory limit or use fewer threads",
+                file_size, memory_limit_mb, num_threads, MIN_BUCKETS
+            ))
+        })
+    }
+
+    // fills the memory limit with `num_buckets` memory buckets of whole records, each flushed `flush_size` times to
+    // its disk bucket, without going over `file_size`; the records of one round are also rounded down to split
+    // evenly between the threads, since each thread generates its own share of them and a share that can't be met
+    // would never end
+    fn with_buckets(
+        k: u32,
+        num_threads: usize,
+        memory_limit: usize,
+        memory_size: usize,
+        file_size: usize,
+        flush_size: usize,
+        num_buckets: usize,
+    ) -> io::Result<Self> {
+        let bucket_records =
+            (memory_size / num_buckets).min(file_size / flush_size / num_buckets) / RECORD_SIZE;
+        // num_buckets is a power of two, so only the rest of num_threads has to divide the records of a memory bucket
+        let thread_step = num_threads
+            >> num_threads
+                .trailing_zeros()
+                .min(num_buckets.trailing_zeros());
+        let mut write_records = bucket_records / thread_step * thread_step;
+        if write_records == 0 {
+            return Err(invalid(format!(
+                "{} memory buckets in {} bytes, shared by {} threads, leave not even one {}-byte record each; \
+                 raise the memory limit or use fewer threads",
+                num_buckets, memory_size, num_threads, RECORD_SIZE
+            )));
+        }
+        let mut flush_size = flush_size;
+        // rounding down can shrink a vault that needed several flushes until it fits the limit after all; it is
+        // then generated in one round, since the in-memory path never flushes
+        if flush_size > 1 && write_records * flush_size * num_buckets * RECORD_SIZE <= memory_limit
+        {
+            write_records *= flush_size;
+            flush_size = 1;
+        }
+        let write_size = write_records * RECORD_SIZE;
+        let bucket_size = write_records * flush_size;
+        let num_records = bucket_size * num_buckets;
+        let plan = Self {
+            k,
+            num_threads,
+            memory_limit,
+            memory_size: write_size * num_buckets,
+            file_size: num_records * RECORD_SIZE,
+            num_records,
+            write_size,
+            flush_size,
+            bucket_size,
+            num_buckets,
+            prefix_size: num_buckets.trailing_zeros(),
+            expected_total_flushes: flush_size * num_buckets,
+            sort_memory: write_size * flush_size * num_threads,
+            in_memory: flush_size == 1,
+        };
+
+        // generation fills every disk bucket exactly and each thread its whole share, or it never finishes
+        if plan.bucket_size * plan.num_buckets != plan.num_records
+            || plan.memory_size % (plan.num_threads * RECORD_SIZE) != 0
+        {
+            return Err(invalid(format!(
+                "inconsistent plan: {} buckets of {} records for {} records, {} bytes per round for {} threads",
+                plan.num_buckets,
+                plan.bucket_size,
+                plan.num_records,
+                plan.memory_size,
+                plan.num_threads
+            )));
+        }
+        Ok(plan)
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Plan for k={} with {} threads and a {} MB memory limit:",
+            self.k,
+            self.num_threads,
+            self.memory_limit / 1024 / 1024
+        );
+        println!(
+            "Memory size: {} bytes ({} GB)",
+            self.memory_size,
+            self.memory_size / 1024 / 1024 / 1024
+        );
+        println!(
+            "File size: {} bytes ({} GB)",
+            self.file_size,
+            self.file_size / 1024 / 1024 / 1024
+        );
+        println!(
+            "Write size [memory bucket size]: {} bytes ({} MB)",
+            self.write_size,
+            self.write_size / 1024 / 1024
+        ); // memory bucket size
+        println!("Flush size: {}", self.flush_size); // how many times the flush happens
+        println!("Disk bucket size (in records): {}", self.bucket_size); // records in 1 disk bucket
+        println!("Num buckets: {}", self.num_buckets);
+        println!("Prefix size: {} bits", self.prefix_size);
+        println!("Expected total flushes: {}", self.expected_total_flushes);
+        println!(
+            "Sort memory: {} bytes ({} MB)",
+            self.sort_memory,
+            self.sort_memory / 1024 / 1024
+        );
+        println!("Number of records: {}", self.num_records);
+        if self.in_memory {
+            println!("Vault fits in memory: generating, sorting and writing it in a single pass");
+        } else if self.sort_memory > self.memory_limit {
+            println!("Buckets don't fit a thread's share of memory: each one is sorted externally in runs");
+        }
+    }
+
+    pub fn report(&self, format: OutputFormat) -> io::Result<()> {
+        if format.is_table() {
+            self.print();
+            Ok(())
+        } else {
+            report::emit_object(format, "plot_plan", self)
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what every plan has to satisfy for generation and sorting to be correct
+    fn assert_consistent(plan: &PlotPlan) {
+        assert!(plan.num_buckets.is_power_of_two());
+        assert!(plan.num_buckets >= MIN_BUCKETS);
+        assert_eq!(1usize << plan.prefix_size, plan.num_buckets);
+        assert_eq!(plan.write_size % RECORD_SIZE, 0);
+        assert!(plan.write_size >= RECORD_SIZE);
+        assert_eq!(plan.memory_size % (plan.num_threads * RECORD_SIZE), 0);
+        assert_eq!(plan.memory_size, plan.write_size * plan.num_buckets);
+        assert!(plan.memory_size <= plan.memory_limit);
+        assert_eq!(plan.file_size, plan.num_records * RECORD_SIZE);
+        assert_eq!(plan.bucket_size * plan.num_buckets, plan.num_records);
+        assert_eq!(
+            plan.file_size,
+            plan.write_size * plan.flush_size * plan.num_buckets
+        );
+        assert_eq!(plan.in_memory, plan.file_size <= plan.memory_limit);
+        assert_eq!(plan.in_memory, plan.flush_size == 1);
+    }
+
+    #[test]
+    fn every_record_is_planned_when_the_vault_fits_in_memory() {
+        for k in 10..=26 {
+            for threads in [1, 4, 16] {
+                let plan = PlotPlan::new(k, None, 2048, threads).unwrap();
+                assert_consistent(&plan);
+                assert_eq!(plan.num_records, 1 << k, "k={} threads={}", k, threads);
+                assert_eq!(plan.flush_size, 1);
+                assert!(plan.in_memory);
+            }
+        }
+    }
+
+    #[test]
+    fn vaults_larger_than_memory_are_flushed_several_times() {
+        let plan = PlotPlan::new(30, None, 2048, 8).unwrap();
+        assert_consistent(&plan);
+        assert_eq!(plan.flush_size, 16);
+        assert_eq!(plan.num_records, 1 << 30);
+        assert!(!plan.in_memory);
+        assert!(plan.sort_memory <= plan.memory_size);
+    }
+
+    #[test]
+    fn plans_are_consistent_across_k_and_memory_limits() {
+        let mut valid = 0;
+        for k in 8..=34 {
+            for memory_limit_mb in [1, 16, 256, 2048, 16384] {
+                for threads in [1, 8] {
+                    if let Ok(plan) = PlotPlan::new(k, None, memory_limit_mb, threads) {
+                        assert_consistent(&plan);
+                        valid += 1;
+                    }
+                }
+            }
+        }
+        assert!(valid > 100, "only {} plans were valid", valid);
+    }
+
+    #[test]
+    fn a_smaller_memory_limit_never_needs_more_memory() {
+        for k in 20..=30 {
+            let large = PlotPlan::new(k, None, 4096, 4).unwrap();
+            let small = PlotPlan::new(k, None, 512, 4).unwrap();
+            assert!(small.memory_size <= large.memory_size, "k={}", k);
+            assert!(small.flush_size >= large.flush_size, "k={}", k);
+        }
+    }
+
+    #[test]
+    fn an_explicit_file_size_replaces_k() {
+        let plan = PlotPlan::new(20, Some(64 * 1024 * 1024), 2048, 1).unwrap();
+        assert_consistent(&plan);
+        assert_eq!(plan.file_size, 64 * 1024 * 1024);
+    }
+
+    #[test]
+    fn an_odd_file_size_is_rounded_down_to_whole_buckets() {
+        // 65568 records don't split into 64 buckets of whole records
+        let plan = PlotPlan::new(16, Some(2098176), 16, 1).unwrap();
+        assert_consistent(&plan);
+        assert_eq!(plan.num_records, 65536);
+        assert_eq!(plan.bucket_size, 1024);
+
+        for file_size in [
+            2098176,
+            2098176 + 16,
+            64 * 1024 * 1024 + 32 * 3,
+            100_000_000,
+        ] {
+            let plan = PlotPlan::new(20, Some(file_size), 16, 1).unwrap();
+            assert_consistent(&plan);
+            assert!(plan.file_size <= file_size, "file size {}", file_size);
+        }
+    }
+
+    #[test]
+    fn a_vault_rounded_down_into_memory_is_generated_in_one_round() {
+        // 1 MB + 32 bytes needs two flushes of 1 MB, but rounds down to a vault that fits in 1 MB
+        let plan = PlotPlan::new(15, Some(1048608), 1, 1).unwrap();
+        assert_consistent(&plan);
+        assert!(plan.in_memory);
+        assert_eq!(plan.flush_size, 1);
+        assert_eq!(plan.num_records, 32768);
+        assert_eq!(plan.memory_size, plan.file_size);
+    }
+
+    #[test]
+    fn every_thread_gets_a_share_of_whole_records() {
+        for (k, memory_limit_mb, threads) in [
+            (18, 2, 3),
+            (14, 16, 3),
+            (20, 16, 6),
+            (24, 64, 7),
+            (22, 8, 12),
+        ] {
+            let plan = PlotPlan::new(k, None, memory_limit_mb, threads).unwrap();
+            assert_consistent(&plan);
+        }
+    }
+
+    #[test]
+    fn buckets_that_dont_sort_in_memory_are_as_small_as_possible() {
+        // 32 GB in 1 MB: no bucket layout sorts in memory, so the one with the most buckets is taken
+        let plan = PlotPlan::new(30, None, 1, 1).unwrap();
+        assert_consistent(&plan);
+        assert!(plan.sort_memory > plan.memory_limit);
+        assert_eq!(plan.num_buckets, 2048);
+
+        let plan = PlotPlan::new(20, None, 1, 128).unwrap();
+        assert_consistent(&plan);
+        assert!(plan.sort_memory > plan.memory_limit);
+    }
+
+    #[test]
+    fn too_many_threads_for_the_memory_is_an_error() {
+        assert!(PlotPlan::new(20, None, 1, 1).is_ok());
+        // 1025 threads can't split the few records of a memory bucket between them
+        let error = PlotPlan::new(20, None, 1, 1025).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("no bucket layout"), "{}", error);
+    }
+
+    #[test]
+    fn vaults_too_small_for_the_minimum_bucket_count_are_an_error() {
+        assert!(PlotPlan::new(5, None, 2048, 1).is_err());
+    }
+
+    #[test]
+    fn invalid_inputs_are_rejected() {
+        assert!(PlotPlan::new(0, None, 2048, 1).is_err());
+        assert!(PlotPlan::new(MAX_K + 1, None, 2048, 1).is_err());
+        assert!(PlotPlan::new(20, None, 0, 1).is_err());
+        assert!(PlotPlan::new(20, None, 2048, 0).is_err());
+        assert!(PlotPlan::new(20, Some(16), 2048, 1).is_err());
+    }
+}
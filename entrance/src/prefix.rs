@@ -0,0 +1,287 @@
+// this file parses lookup prefixes (hex, binary or base64) into raw bytes plus a length in bits
+use crate::HASH_SIZE;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+
+const MAX_PREFIX_BITS: usize = HASH_SIZE * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixFormat {
+    Hex,
+    Binary,
+    Base64,
+}
+
+impl PrefixFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hex" => Some(PrefixFormat::Hex),
+            "bin" | "binary" => Some(PrefixFormat::Binary),
+            "base64" => Some(PrefixFormat::Base64),
+            _ => None,
+        }
+    }
+}
+
+// the leading `bits` bits of `bytes`; everything after them is zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    bytes: [u8; HASH_SIZE],
+    bits: usize,
+}
+
+impl Prefix {
+    // parses `input` in the given format; `bits` shortens the prefix, and defaults to every bit the input spells out
+    pub fn parse(input: &str, format: PrefixFormat, bits: Option<usize>) -> io::Result<Self> {
+        let (bytes, available_bits) = match format {
+            PrefixFormat::Hex => parse_digits(input, 4, |c| c.to_digit(16), "hex")?,
+            PrefixFormat::Binary => parse_digits(input, 1, |c| c.to_digit(2), "binary")?,
+            PrefixFormat::Base64 => decode_base64(input)?,
+        };
+
+        let bits = bits.unwrap_or(available_bits);
+        if bits == 0 {
+            return Err(invalid("a prefix needs at least one bit"));
+        }
+        if bits > available_bits {
+            return Err(invalid(&format!(
+                "prefix '{}' only has {} bits, but {} were requested",
+                input, available_bits, bits
+            )));
+        }
+        Ok(Self::from_bytes(&bytes, bits))
+    }
+
+    // takes the leading `bits` bits of `bytes` (at most a whole hash)
+    pub fn from_bytes(bytes: &[u8], bits: usize) -> Self {
+        let bits = bits.min(MAX_PREFIX_BITS).min(bytes.len() * 8);
+        let mut prefix = [0u8; HASH_SIZE];
+        let len = bits.div_ceil(8);
+        prefix[..len].copy_from_slice(&bytes[..len]);
+        if bits % 8 != 0 {
+            prefix[len - 1] &= 0xff << (8 - bits % 8);
+        }
+        Self {
+            bytes: prefix,
+            bits,
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn bytes(&self) -> &[u8; HASH_SIZE] {
+        &self.bytes
+    }
+
+    // hex of the bytes the prefix touches (the unused low bits of the last one are zero)
+    pub fn hex(&self) -> String {
+        self.bytes[..self.bits.div_ceil(8)]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // compares the leading `bits` bits of `hash` with this prefix, without allocating
+    #[inline]
+    pub fn compare(&self, hash: &[u8]) -> Ordering {
+        let full_bytes = self.bits / 8;
+        let ordering = hash[..full_bytes].cmp(&self.bytes[..full_bytes]);
+        if ordering != Ordering::Equal || self.bits % 8 == 0 {
+            return ordering;
+        }
+        let mask = 0xffu8 << (8 - self.bits % 8);
+        (hash[full_bytes] & mask).cmp(&self.bytes[full_bytes])
+    }
+}
+
+impl fmt::Display for Prefix {
+    // hex of the bytes covered by the prefix, plus its exact length in bits
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.hex(), self.bits)
+    }
+}
+
+// packs digits of `bits_per_digit` bits each (hex or binary) into bytes, most significant first
+fn parse_digits(
+    input: &str,
+    bits_per_digit: usize,
+    to_digit: impl Fn(char) -> Option<u32>,
+    name: &str,
+) -> io::Result<([u8; HASH_SIZE], usize)> {
+    if input.is_empty() {
+        return Err(invalid(&format!("empty {} prefix", name)));
+    }
+
+    let mut bytes = [0u8; HASH_SIZE];
+    let mut bit = 0;
+    for (position, c) in input.chars().enumerate() {
+        let digit = to_digit(c).ok_or_else(|| {
+            invalid(&format!(
+                "'{}' in prefix '{}' (position {}) is not a {} digit",
+                c, input, position, name
+            ))
+        })?;
+        if bit + bits_per_digit > MAX_PREFIX_BITS {
+            return Err(invalid(&format!(
+                "prefix '{}' is longer than a {}-bit hash",
+                input, MAX_PREFIX_BITS
+            )));
+        }
+        for shift in (0..bits_per_digit).rev() {
+            if digit >> shift & 1 == 1 {
+                bytes[bit / 8] |= 0x80 >> (bit % 8);
+            }
+            bit += 1;
+        }
+    }
+    Ok((bytes, bit))
+}
+
+// standard base64 (RFC 4648), with or without '=' padding
+fn decode_base64(input: &str) -> io::Result<([u8; HASH_SIZE], usize)> {
+    let trimmed = input.trim_end_matches('=');
+    if trimmed.is_empty() {
+        return Err(invalid("empty base64 prefix"));
+    }
+
+    let mut bytes = [0u8; HASH_SIZE];
+    let mut len = 0;
+    let mut buffer = 0u32;
+    let mut buffered_bits = 0;
+    for (position, c) in trimmed.chars().enumerate() {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' => 62,
+            '/' => 63,
+            _ => {
+                return Err(invalid(&format!(
+                    "'{}' in prefix '{}' (position {}) is not a base64 character",
+                    c, input, position
+                )))
+            }
+        };
+        buffer = buffer << 6 | value;
+        buffered_bits += 6;
+        if buffered_bits >= 8 {
+            buffered_bits -= 8;
+            if len == HASH_SIZE {
+                return Err(invalid(&format!(
+                    "prefix '{}' is longer than a {}-bit hash",
+                    input, MAX_PREFIX_BITS
+                )));
+            }
+            bytes[len] = (buffer >> buffered_bits) as u8;
+            len += 1;
+        }
+    }
+    if len == 0 {
+        return Err(invalid(&format!(
+            "base64 prefix '{}' doesn't decode to a whole byte",
+            input
+        )));
+    }
+    Ok((bytes, len * 8))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str, format: PrefixFormat, bits: Option<usize>) -> Prefix {
+        Prefix::parse(input, format, bits).unwrap()
+    }
+
+    #[test]
+    fn every_format_spells_the_same_prefix() {
+        let hex = parse("a5f0", PrefixFormat::Hex, None);
+        assert_eq!(hex.bits(), 16);
+        assert_eq!(hex.bytes()[..3], [0xa5, 0xf0, 0x00]);
+        assert_eq!(hex, parse("A5F0", PrefixFormat::Hex, None));
+        assert_eq!(hex, parse("1010010111110000", PrefixFormat::Binary, None));
+        assert_eq!(hex, parse("pfA=", PrefixFormat::Base64, None));
+        assert_eq!(hex, parse("pfA", PrefixFormat::Base64, None));
+        assert_eq!(hex.to_string(), "a5f0/16");
+    }
+
+    #[test]
+    fn prefixes_can_end_inside_a_byte() {
+        let binary = parse("10101", PrefixFormat::Binary, None);
+        assert_eq!(binary.bits(), 5);
+        assert_eq!(binary.bytes()[0], 0b1010_1000);
+        assert_eq!(binary.hex(), "a8");
+
+        // the requested bits cut off everything after them
+        let hex = parse("ab", PrefixFormat::Hex, Some(5));
+        assert_eq!(hex, binary);
+        assert_eq!(hex.to_string(), "a8/5");
+    }
+
+    #[test]
+    fn compare_only_looks_at_the_prefix_bits() {
+        let prefix = parse("10101", PrefixFormat::Binary, None);
+        assert_eq!(prefix.compare(&[0b1010_1000]), Ordering::Equal);
+        assert_eq!(prefix.compare(&[0b1010_1111]), Ordering::Equal);
+        assert_eq!(prefix.compare(&[0b1010_0111]), Ordering::Less);
+        assert_eq!(prefix.compare(&[0b1011_0000]), Ordering::Greater);
+
+        let prefix = parse("00ab", PrefixFormat::Hex, None);
+        assert_eq!(prefix.compare(&[0x00, 0xab, 0xff]), Ordering::Equal);
+        assert_eq!(prefix.compare(&[0x00, 0xaa, 0xff]), Ordering::Less);
+        assert_eq!(prefix.compare(&[0x01, 0x00, 0x00]), Ordering::Greater);
+    }
+
+    #[test]
+    fn a_whole_hash_is_the_longest_prefix() {
+        let full = "ff".repeat(HASH_SIZE);
+        assert_eq!(
+            parse(&full, PrefixFormat::Hex, None).bits(),
+            MAX_PREFIX_BITS
+        );
+        assert!(Prefix::parse(&(full + "f"), PrefixFormat::Hex, None).is_err());
+        assert_eq!(
+            Prefix::from_bytes(&[0xff; 64], 1000).bits(),
+            MAX_PREFIX_BITS
+        );
+    }
+
+    #[test]
+    fn malformed_prefixes_are_rejected() {
+        for (input, format, bits) in [
+            ("", PrefixFormat::Hex, None),
+            ("0g", PrefixFormat::Hex, None),
+            ("0102", PrefixFormat::Binary, None),
+            ("ab", PrefixFormat::Hex, Some(0)),
+            ("ab", PrefixFormat::Hex, Some(9)),
+            ("p", PrefixFormat::Base64, None),
+            ("pf!A", PrefixFormat::Base64, None),
+            ("==", PrefixFormat::Base64, None),
+        ] {
+            let error = Prefix::parse(input, format, bits).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn format_names_are_parsed() {
+        assert_eq!(PrefixFormat::from_name("hex"), Some(PrefixFormat::Hex));
+        assert_eq!(PrefixFormat::from_name("bin"), Some(PrefixFormat::Binary));
+        assert_eq!(
+            PrefixFormat::from_name("binary"),
+            Some(PrefixFormat::Binary)
+        );
+        assert_eq!(
+            PrefixFormat::from_name("base64"),
+            Some(PrefixFormat::Base64)
+        );
+        assert_eq!(PrefixFormat::from_name("octal"), None);
+    }
+}
@@ -1,8 +1,11 @@
 // this file prints records specified by the command line flag: -p
-use crate::{Record, HASH_SIZE, OUTPUT_FOLDER};
+use crate::report::{self, OutputFormat};
+use crate::{vault, Record, HASH_SIZE, RECORD_SIZE};
 use bincode::deserialize_from;
+use serde::Serialize;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, Read};
+use std::path::Path;
 
 // converts nonce from byte array to a decimal value
 fn nonce_to_decimal(nonce: &[u8; 6]) -> u64 {
@@ -17,11 +20,60 @@ fn hash_to_string(hash: &[u8; HASH_SIZE]) -> String {
         .join("")
 }
 
+#[derive(Serialize)]
+struct PrintSummary {
+    vault: String,
+    finalized: bool,
+    num_records: u64,
+    records_printed: usize,
+}
+
+#[derive(Serialize, Default)]
+struct PrintedRecord {
+    record_index: u64,
+    nonce: u64,
+    hash_hex: String,
+}
+
 // this function reads the records from the output file, deserializes them and then prints them
-pub fn print_records_from_file(num_records_print: u64) -> io::Result<()> {
-    let path = format!("{}/output.bin", OUTPUT_FOLDER);
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
+pub fn print_records_from_file(
+    path: &Path,
+    num_records_print: u64,
+    format: OutputFormat,
+) -> io::Result<()> {
+    // printing is a debugging aid, so an unfinished vault is still shown, but never silently
+    let (file, num_records, finalized) = match vault::open_vault(path) {
+        Ok((file, footer)) => (file, footer.num_records, true),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("Warning: {}; printing its raw contents anyway", e);
+            let file = File::open(path)?;
+            let num_records = file.metadata()?.len() / RECORD_SIZE as u64;
+            (file, num_records, false)
+        }
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file.take(num_records * RECORD_SIZE as u64));
+
+    if !format.is_table() {
+        let mut rows = Vec::new();
+        while (rows.len() as u64) < num_records_print {
+            match deserialize_from::<_, Record>(&mut reader) {
+                Ok(record) => rows.push(PrintedRecord {
+                    record_index: rows.len() as u64,
+                    nonce: nonce_to_decimal(&record.nonce),
+                    hash_hex: hash_to_string(&record.hash),
+                }),
+                Err(_) => break,
+            }
+        }
+        let summary = PrintSummary {
+            vault: path.display().to_string(),
+            finalized,
+            num_records,
+            records_printed: rows.len(),
+        };
+        return report::emit_rows(format, "records", &summary, "records", &rows);
+    }
 
     println!("{:<16} | {:<64}", "Nonce (Decimal)", "Hash (Hex)");
     println!("{}", "-".repeat(88)); // creates a separator line
@@ -29,7 +81,7 @@ pub fn print_records_from_file(num_records_print: u64) -> io::Result<()> {
     let mut counter = 0;
 
     while counter < num_records_print {
-        match deserialize_from::<&mut BufReader<File>, Record>(&mut reader) {
+        match deserialize_from::<_, Record>(&mut reader) {
             Ok(record) => {
                 let nonce_decimal = nonce_to_decimal(&record.nonce);
                 // to print hashes in binary format instead of hex:
@@ -65,48 +117,3 @@ pub fn print_records_from_file(num_records_print: u64) -> io::Result<()> {
     }
     Ok(())
 }
-
-pub fn verify_records_sorted(expected_count: usize) -> io::Result<()> {
-    let path = format!("{}/output.bin", OUTPUT_FOLDER);
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-
-    let mut last_hash = vec![0u8; HASH_SIZE]; // Initially the smallest possible hash
-    let mut is_first = true;
-    let mut record_count = 0;
-
-    loop {
-        match deserialize_from::<&mut BufReader<File>, Record>(&mut reader) {
-            Ok(record) => {
-                record_count += 1;
-
-                if is_first {
-                    last_hash = record.hash.to_vec();
-                    is_first = false;
-                } else {
-                    if last_hash > record.hash.to_vec() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("output.bin is not sorted correctly with hashes",),
-                        ));
-                    }
-                    last_hash = record.hash.to_vec();
-                }
-            }
-            Err(_) => break,
-        }
-    }
-
-    if record_count != expected_count {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "Expected {} records but found {}",
-                expected_count, record_count
-            ),
-        ));
-    }
-
-    println!("output.bin is sorted correctly and contains the expected number of records.");
-    Ok(())
-}
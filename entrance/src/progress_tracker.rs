@@ -81,7 +81,7 @@ impl ProgressTracker {
                 let flushes = self.flushes_completed.load(Ordering::SeqCst);
 
                 let eta = if now_processed > 0 && progress < 100.0 {
-                    (elapsed / progress as f64) * (100.0 - progress) as f64 * 10 as f64
+                    (elapsed / progress as f64) * (100.0 - progress) as f64 * 10_f64
                 } else {
                     0.0
                 };
@@ -93,7 +93,7 @@ impl ProgressTracker {
 
                 // check if the current progress, ETA, or throughput is different from the last logged values
                 let current_log = (progress, eta, throughput);
-                if last_logged_progress.map_or(true, |last| last != current_log) {
+                if last_logged_progress != Some(current_log) {
                     if current_stage.lock().unwrap().clone() == "[SYNCING]" {
                         info!("{}", current_stage.lock().unwrap().clone());
                     } else {
@@ -157,6 +157,14 @@ impl ProgressTracker {
         self.flushes_completed.fetch_add(count, Ordering::SeqCst);
     }
 
+    // resets all counters so one tracker can follow several plots in a row
+    pub fn reset(&self, expected_flushes: usize) {
+        *self.records_processed.lock().unwrap() = 0;
+        *self.stage_records_processed.lock().unwrap() = 0;
+        *self.last_processed_count.lock().unwrap() = 0;
+        self.set_expected_flushes(expected_flushes);
+    }
+
     pub fn set_expected_flushes(&self, flushes: usize) {
         self.flushes_completed.store(0, Ordering::SeqCst);
         self.expected_total_flushes.store(flushes, Ordering::SeqCst);
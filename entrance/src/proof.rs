@@ -0,0 +1,304 @@
+// this file defines a proof of space: the record a vault holds for a challenge, and how anyone checks it
+use crate::hash_generator::generate_hash;
+use crate::lookup::{self, hash_to_string, nonce_to_decimal, SearchStrategy};
+use crate::prefix::{Prefix, PrefixFormat};
+use crate::vault::VaultFooter;
+use crate::{HASH_SIZE, NONCE_SIZE};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+const PROOF_MAGIC: [u8; 8] = *b"VPROOF76";
+const PROOF_VERSION: u32 = 1;
+pub const PROOF_SIZE: usize = 86; // bytes of the binary encoding
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProofFormat {
+    Binary, // fixed-size little-endian layout (bincode), PROOF_SIZE bytes
+    Json,
+}
+
+impl ProofFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "binary" => Some(ProofFormat::Binary),
+            "json" => Some(ProofFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// the binary encoding is this struct as bincode writes it, so fields are never reordered, only appended
+// under a new version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proof {
+    magic: [u8; 8],
+    version: u32,
+    pub plot_id: u64,
+    pub k: u32,
+    pub challenge: [u8; HASH_SIZE], // only the leading challenge_bits bits count, the rest are zero
+    pub challenge_bits: u32,
+    pub nonce: [u8; NONCE_SIZE],
+    pub hash: [u8; HASH_SIZE],
+}
+
+// the JSON encoding; hashes are hex, the plot id is 16 hex digits like in vault file names
+#[derive(Serialize, Deserialize)]
+struct ProofJson {
+    #[serde(rename = "type")]
+    kind: String,
+    schema_version: u32,
+    plot_id: String,
+    k: u32,
+    challenge_hex: String,
+    challenge_bits: u32,
+    nonce: u64,
+    hash_hex: String,
+}
+
+impl Proof {
+    pub fn new(
+        footer: &VaultFooter,
+        challenge: &Prefix,
+        nonce: [u8; NONCE_SIZE],
+        hash: [u8; HASH_SIZE],
+    ) -> Self {
+        Self {
+            magic: PROOF_MAGIC,
+            version: PROOF_VERSION,
+            plot_id: footer.plot_id,
+            k: footer.k,
+            challenge: *challenge.bytes(),
+            challenge_bits: challenge.bits() as u32,
+            nonce,
+            hash,
+        }
+    }
+
+    pub fn challenge(&self) -> Prefix {
+        Prefix::from_bytes(&self.challenge, self.challenge_bits as usize)
+    }
+
+    // needs nothing but the proof: the hash must be BLAKE3 of the nonce and must start with the challenge
+    pub fn verify(&self) -> Result<(), String> {
+        if self.magic != PROOF_MAGIC || self.version != PROOF_VERSION {
+            return Err(format!("unsupported proof version {}", self.version));
+        }
+        if self.challenge_bits == 0 || self.challenge_bits as usize > HASH_SIZE * 8 {
+            return Err(format!(
+                "a challenge must have between 1 and {} bits, not {}",
+                HASH_SIZE * 8,
+                self.challenge_bits
+            ));
+        }
+        let challenge = self.challenge();
+        if challenge.bytes() != &self.challenge {
+            return Err("the challenge has bits set past its length".to_string());
+        }
+
+        let (_, record) = generate_hash(nonce_to_decimal(&self.nonce), 0);
+        if record.hash != self.hash {
+            return Err(format!(
+                "BLAKE3 of nonce {} is {}, not {}",
+                nonce_to_decimal(&self.nonce),
+                hash_to_string(&record.hash),
+                hash_to_string(&self.hash)
+            ));
+        }
+        if challenge.compare(&self.hash) != std::cmp::Ordering::Equal {
+            return Err(format!(
+                "hash {} does not start with challenge {}",
+                hash_to_string(&self.hash),
+                challenge
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, format: ProofFormat) -> io::Result<Vec<u8>> {
+        match format {
+            ProofFormat::Binary => {
+                bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            ProofFormat::Json => {
+                let json = ProofJson {
+                    kind: "proof".to_string(),
+                    schema_version: PROOF_VERSION,
+                    plot_id: format!("{:016x}", self.plot_id),
+                    k: self.k,
+                    challenge_hex: self.challenge().hex(),
+                    challenge_bits: self.challenge_bits,
+                    nonce: nonce_to_decimal(&self.nonce),
+                    hash_hex: hash_to_string(&self.hash),
+                };
+                let mut encoded = serde_json::to_vec_pretty(&json)?;
+                encoded.push(b'\n');
+                Ok(encoded)
+            }
+        }
+    }
+
+    // reads either encoding, telling them apart by the binary magic
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.starts_with(&PROOF_MAGIC) {
+            if bytes.len() != PROOF_SIZE {
+                return Err(invalid(format!(
+                    "a binary proof is {} bytes, not {}",
+                    PROOF_SIZE,
+                    bytes.len()
+                )));
+            }
+            return bincode::deserialize(bytes).map_err(|e| invalid(e.to_string()));
+        }
+
+        let json: ProofJson = serde_json::from_slice(bytes)?;
+        if json.kind != "proof" || json.schema_version != PROOF_VERSION {
+            return Err(invalid(format!(
+                "expected a version {} proof, got a version {} {}",
+                PROOF_VERSION, json.schema_version, json.kind
+            )));
+        }
+        if json.nonce >= 1u64 << (NONCE_SIZE * 8) {
+            return Err(invalid(format!(
+                "nonce {} does not fit in 6 bytes",
+                json.nonce
+            )));
+        }
+        let plot_id = u64::from_str_radix(&json.plot_id, 16)
+            .map_err(|_| invalid(format!("'{}' is not a hex plot id", json.plot_id)))?;
+        let challenge = Prefix::parse(
+            &json.challenge_hex,
+            PrefixFormat::Hex,
+            Some(json.challenge_bits as usize),
+        )?;
+        let hash = Prefix::parse(&json.hash_hex, PrefixFormat::Hex, Some(HASH_SIZE * 8))?;
+        Ok(Self {
+            magic: PROOF_MAGIC,
+            version: PROOF_VERSION,
+            plot_id,
+            k: json.k,
+            challenge: *challenge.bytes(),
+            challenge_bits: challenge.bits() as u32,
+            nonce: json.nonce.to_be_bytes()[8 - NONCE_SIZE..]
+                .try_into()
+                .unwrap(),
+            hash: *hash.bytes(),
+        })
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// answers a challenge with the first record of the vault whose hash starts with it, if there is one
+pub fn prove(file: &File, footer: &VaultFooter, challenge: &Prefix) -> io::Result<Option<Proof>> {
+    // a limit of 0 stops at the first match, which is all a proof needs
+    let (records, _) =
+        lookup::find_by_prefix_limited(file, footer, challenge, SearchStrategy::Interpolation, 0)?;
+    Ok(records
+        .first()
+        .map(|record| Proof::new(footer, challenge, record.nonce, record.hash)))
+}
+
+pub fn prove_from_vault(path: &Path, challenge: &Prefix) -> io::Result<Option<Proof>> {
+    let (file, footer) = lookup::open_sorted_vault(path)?;
+    prove(&file, &footer, challenge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a valid proof for the record of `nonce`, challenged with the leading `bits` bits of its own hash
+    fn proof(nonce: u64, bits: usize) -> Proof {
+        let (_, record) = generate_hash(nonce, 0);
+        let footer = VaultFooter::new(20, 6, true, 64, 16384, 1 << 20, 0x0123456789abcdef);
+        let challenge = Prefix::from_bytes(&record.hash, bits);
+        Proof::new(&footer, &challenge, record.nonce, record.hash)
+    }
+
+    #[test]
+    fn proofs_of_a_record_verify() {
+        for bits in [1, 7, 8, 20, HASH_SIZE * 8] {
+            assert_eq!(
+                proof(46194004114119, bits).verify(),
+                Ok(()),
+                "bits={}",
+                bits
+            );
+        }
+    }
+
+    #[test]
+    fn both_encodings_decode_to_the_same_proof() {
+        let original = proof(1234567, 21);
+        let binary = original.encode(ProofFormat::Binary).unwrap();
+        assert_eq!(binary.len(), PROOF_SIZE);
+        assert!(binary.starts_with(&PROOF_MAGIC));
+        assert_eq!(Proof::decode(&binary).unwrap(), original);
+
+        let json = original.encode(ProofFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["type"], "proof");
+        assert_eq!(value["plot_id"], "0123456789abcdef");
+        assert_eq!(value["nonce"], 1234567);
+        assert_eq!(Proof::decode(&json).unwrap(), original);
+    }
+
+    #[test]
+    fn tampered_proofs_fail_to_verify() {
+        let mut wrong_hash = proof(99, 16);
+        wrong_hash.hash[HASH_SIZE - 1] ^= 1;
+        assert!(wrong_hash.verify().unwrap_err().contains("BLAKE3"));
+
+        let mut wrong_nonce = proof(99, 16);
+        wrong_nonce.nonce = proof(100, 16).nonce;
+        assert!(wrong_nonce.verify().is_err());
+
+        let mut wrong_challenge = proof(99, 16);
+        wrong_challenge.challenge[0] ^= 0x80;
+        assert!(wrong_challenge
+            .verify()
+            .unwrap_err()
+            .contains("does not start with"));
+
+        let mut stray_bits = proof(99, 16);
+        stray_bits.challenge[HASH_SIZE - 1] = 1;
+        assert!(stray_bits.verify().unwrap_err().contains("past its length"));
+
+        for challenge_bits in [0, HASH_SIZE as u32 * 8 + 1] {
+            let mut bad_length = proof(99, 16);
+            bad_length.challenge_bits = challenge_bits;
+            assert!(bad_length.verify().is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_encodings_are_rejected() {
+        let binary = proof(5, 12).encode(ProofFormat::Binary).unwrap();
+        assert!(Proof::decode(&binary[..PROOF_SIZE - 1]).is_err());
+        assert!(Proof::decode(b"not a proof").is_err());
+
+        let json = |nonce: u64, kind: &str| {
+            format!(
+                r#"{{"type":"{}","schema_version":1,"plot_id":"00","k":20,"challenge_hex":"ab","challenge_bits":8,"nonce":{},"hash_hex":"{}"}}"#,
+                kind,
+                nonce,
+                "00".repeat(HASH_SIZE)
+            )
+        };
+        assert!(Proof::decode(json(5, "proof").as_bytes()).is_ok());
+        assert!(Proof::decode(json(5, "vault").as_bytes()).is_err());
+        assert!(Proof::decode(json(1 << 48, "proof").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn format_names_are_parsed() {
+        assert_eq!(ProofFormat::from_name("binary"), Some(ProofFormat::Binary));
+        assert_eq!(ProofFormat::from_name("json"), Some(ProofFormat::Json));
+        assert_eq!(ProofFormat::from_name("bin"), None);
+    }
+}
@@ -0,0 +1,253 @@
+// this file scores how good a plot's answer to a challenge is, and whether it beats a difficulty target
+use crate::closest::{self, Distance};
+use crate::farm::{Farm, Plot};
+use crate::lookup::{hash_to_string, nonce_to_decimal};
+use crate::prefix::Prefix;
+use crate::report::{self, OutputFormat};
+use crate::HASH_SIZE;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+// the quality of a hash is how many leading bits it shares with the challenge, counted fractionally:
+// -log2 of their XOR distance read as a fraction in [0, 1), so each extra bit is half as likely to happen.
+// a plot of N records is expected to reach about log2(N) bits, so bigger plots win more often
+pub fn quality(challenge: &[u8; HASH_SIZE], hash: &[u8; HASH_SIZE]) -> f64 {
+    let mut distance = [0u8; HASH_SIZE];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = challenge[i] ^ hash[i];
+    }
+    distance_quality(&distance)
+}
+
+fn distance_quality(distance: &[u8; HASH_SIZE]) -> f64 {
+    let fraction = distance
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| byte as f64 * 2f64.powi(-8 * (i as i32 + 1)))
+        .sum::<f64>();
+    if fraction == 0.0 {
+        (HASH_SIZE * 8) as f64
+    } else {
+        -fraction.log2()
+    }
+}
+
+// the quality minus what a plot of this size is expected to reach: above zero is a luckier than usual answer.
+// it is only reported, never compared against the difficulty: the raw quality already grows by log2(N) with the
+// plot's size, which is what lets a plot holding more space win more often, and judging by luck would undo that
+pub fn luck(quality: f64, num_records: u64) -> f64 {
+    quality - (num_records.max(1) as f64).log2()
+}
+
+// the best answer one plot has for a challenge; `evaluate` returns one per plot, in `Farm::plots` order
+pub struct PlotQuality {
+    pub nonce: u64,
+    pub hash: [u8; HASH_SIZE],
+    pub quality: f64,
+    pub luck: f64,
+}
+
+impl PlotQuality {
+    // whether this answer wins at `difficulty` bits; the raw quality decides, see `luck`
+    pub fn reaches(&self, difficulty: f64) -> bool {
+        self.quality >= difficulty
+    }
+}
+
+#[derive(Serialize)]
+struct QualitySummary {
+    farm: String,
+    num_plots: usize,
+    challenge_hex: String,
+    challenge_bits: usize,
+    difficulty_bits: f64,
+    winners: usize,
+    best_quality_bits: Option<f64>,
+    duration_us: f64,
+}
+
+#[derive(Serialize, Default)]
+struct QualityRow {
+    plot: String,
+    plot_id: String,
+    k: u32,
+    num_records: u64,
+    nonce: Option<u64>,
+    hash_hex: Option<String>,
+    quality_bits: Option<f64>,
+    luck_bits: Option<f64>,
+    wins: bool,
+}
+
+// the record of the plot nearest the challenge under XOR, found through the closest-match lookup;
+// a plot without any record in the challenge's bucket or the one next to it has no answer
+pub fn evaluate_plot(plot: &Plot, challenge: &[u8; HASH_SIZE]) -> io::Result<Option<PlotQuality>> {
+    let result = closest::find_closest(
+        &plot.file,
+        &plot.footer,
+        challenge,
+        Distance::Xor,
+        1,
+        1,
+        plot.cached_index(),
+    )?;
+    Ok(result.matches.first().map(|found| {
+        let quality = distance_quality(&found.distance);
+        PlotQuality {
+            nonce: nonce_to_decimal(&found.nonce),
+            hash: found.hash,
+            quality,
+            luck: luck(quality, plot.footer.num_records),
+        }
+    }))
+}
+
+// every plot of the farm evaluated in parallel
+pub fn evaluate(farm: &Farm, challenge: &Prefix) -> io::Result<Vec<Option<PlotQuality>>> {
+    farm.plots
+        .par_iter()
+        .map(|plot| evaluate_plot(plot, challenge.bytes()))
+        .collect()
+}
+
+// reports, for every plot of the farm, its best answer to the challenge and whether it reaches `difficulty` bits
+pub fn report_farm_quality(
+    source: &Path,
+    challenge: &Prefix,
+    difficulty: f64,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let farm = Farm::open(source)?;
+
+    let start_time = Instant::now();
+    let answers = evaluate(&farm, challenge)?;
+    let duration = start_time.elapsed();
+
+    let rows = farm
+        .plots
+        .iter()
+        .zip(&answers)
+        .map(|(plot, answer)| QualityRow {
+            plot: plot.path.display().to_string(),
+            plot_id: plot.id_hex(),
+            k: plot.footer.k,
+            num_records: plot.footer.num_records,
+            nonce: answer.as_ref().map(|answer| answer.nonce),
+            hash_hex: answer.as_ref().map(|answer| hash_to_string(&answer.hash)),
+            quality_bits: answer.as_ref().map(|answer| answer.quality),
+            luck_bits: answer.as_ref().map(|answer| answer.luck),
+            wins: answer
+                .as_ref()
+                .is_some_and(|answer| answer.reaches(difficulty)),
+        })
+        .collect::<Vec<QualityRow>>();
+    let winners = rows.iter().filter(|row| row.wins).count();
+    let best_quality = answers
+        .iter()
+        .flatten()
+        .map(|answer| answer.quality)
+        .reduce(f64::max);
+
+    if !format.is_table() {
+        let summary = QualitySummary {
+            farm: source.display().to_string(),
+            num_plots: farm.plots.len(),
+            challenge_hex: challenge.hex(),
+            challenge_bits: challenge.bits(),
+            difficulty_bits: difficulty,
+            winners,
+            best_quality_bits: best_quality,
+            duration_us: duration.as_secs_f64() * 1e6,
+        };
+        return report::emit_rows(format, "farm_quality", &summary, "plots", &rows);
+    }
+
+    println!(
+        "{:<16} | {:<3} | {:<16} | {:<52} | {:>8} | {:>7} | {:<4}",
+        "Plot ID", "k", "Nonce (Decimal)", "Hash (Hex)", "Quality", "Luck", "Wins"
+    );
+    println!("{}", "-".repeat(126));
+    for row in &rows {
+        match (row.nonce, &row.hash_hex, row.quality_bits, row.luck_bits) {
+            (Some(nonce), Some(hash_hex), Some(quality), Some(luck)) => println!(
+                "{} | {:<3} | {:<16} | {} | {:>8.3} | {:>+7.3} | {}",
+                row.plot_id,
+                row.k,
+                nonce,
+                hash_hex,
+                quality,
+                luck,
+                if row.wins { "yes" } else { "no" }
+            ),
+            _ => println!(
+                "{} | {:<3} | {:<16} | {:<52} | {:>8} | {:>7} | no",
+                row.plot_id, row.k, "-", "no record near the challenge", "-", "-"
+            ),
+        }
+    }
+    println!(
+        "{} of {} plots reach difficulty {} bits for challenge {} (best quality {}), evaluated in {:?}",
+        winners,
+        farm.plots.len(),
+        difficulty,
+        challenge,
+        best_quality.map_or("-".to_string(), |quality| format!("{:.3} bits", quality)),
+        duration
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the answer of a plot of `num_records` whose hash first differs from the challenge at bit `bits`
+    fn answer(bits: usize, num_records: u64) -> PlotQuality {
+        let challenge = [0u8; HASH_SIZE];
+        let mut hash = [0u8; HASH_SIZE];
+        hash[bits / 8] = 0x80 >> (bits % 8);
+        let quality = quality(&challenge, &hash);
+        PlotQuality {
+            nonce: 0,
+            hash,
+            quality,
+            luck: luck(quality, num_records),
+        }
+    }
+
+    #[test]
+    fn quality_counts_shared_leading_bits() {
+        assert_eq!(answer(0, 1).quality, 1.0);
+        assert_eq!(answer(7, 1).quality, 8.0);
+        assert_eq!(answer(20, 1).quality, 21.0);
+        assert_eq!(
+            quality(&[0u8; HASH_SIZE], &[0u8; HASH_SIZE]),
+            (HASH_SIZE * 8) as f64
+        );
+    }
+
+    #[test]
+    fn luck_is_quality_over_the_expected_bits() {
+        assert_eq!(answer(20, 1 << 20).luck, 1.0);
+        assert_eq!(answer(20, 1 << 24).luck, -3.0);
+    }
+
+    #[test]
+    fn difficulty_is_reached_by_the_raw_quality() {
+        // exactly at the target wins, a fraction below does not
+        assert!(answer(19, 1 << 20).reaches(20.0));
+        assert!(!answer(18, 1 << 20).reaches(20.0));
+        assert!(!answer(19, 1 << 20).reaches(20.5));
+
+        // a small plot's lucky answer still loses to the target, and a big plot's unlucky one still wins it
+        let small_lucky = answer(15, 1 << 10);
+        assert!(small_lucky.luck > 0.0);
+        assert!(!small_lucky.reaches(20.0));
+        let big_unlucky = answer(23, 1 << 30);
+        assert!(big_unlucky.luck < 0.0);
+        assert!(big_unlucky.reaches(20.0));
+    }
+}
@@ -0,0 +1,152 @@
+// this file writes lookup results, printed records, verify results and run reports as JSON, JSON Lines or CSV
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+
+// bumped whenever a field is renamed or removed; new fields may be added without bumping it
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table, // the human-readable output each command has always printed
+    Json,
+    Jsonl,
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "table" => Some(OutputFormat::Table),
+            "json" => Some(OutputFormat::Json),
+            "jsonl" => Some(OutputFormat::Jsonl),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn is_table(&self) -> bool {
+        *self == OutputFormat::Table
+    }
+}
+
+// writes a report made of a summary and a list of rows to stdout:
+//   json:  {"type": kind, "schema_version": .., <summary fields>, <rows_name>: [<rows>]}
+//   jsonl: one {"type": "<kind>.<rows_name>", <row fields>} line per row, then one {"type": kind, <summary fields>} line
+//   csv:   a header and one line per row (the summary is left out)
+pub fn emit_rows<S: Serialize, R: Serialize + Default>(
+    format: OutputFormat,
+    kind: &str,
+    summary: &S,
+    rows_name: &str,
+    rows: &[R],
+) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    match format {
+        OutputFormat::Table => {}
+        OutputFormat::Json => {
+            let mut document = tagged(kind, summary)?;
+            debug_assert!(
+                !document.contains_key(rows_name),
+                "summary field shadows the rows"
+            );
+            let rows = rows.iter().map(to_object).collect::<io::Result<Vec<_>>>()?;
+            document.insert(
+                rows_name.to_string(),
+                Value::Array(rows.into_iter().map(Value::Object).collect()),
+            );
+            serde_json::to_writer_pretty(&mut out, &document)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Jsonl => {
+            let row_kind = format!("{}.{}", kind, rows_name);
+            for row in rows {
+                serde_json::to_writer(&mut out, &tagged(&row_kind, row)?)?;
+                writeln!(out)?;
+            }
+            serde_json::to_writer(&mut out, &tagged(kind, summary)?)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Csv => {
+            // the header comes from the row type itself, so it is there even when no row is
+            write_csv_line(
+                &mut out,
+                to_object(&R::default())?.keys().map(String::as_str),
+            )?;
+            for row in rows {
+                let row = to_object(row)?;
+                let cells = row.values().map(csv_cell).collect::<Vec<String>>();
+                write_csv_line(&mut out, cells.iter().map(String::as_str))?;
+            }
+        }
+    }
+    out.flush()
+}
+
+// writes a report that is a single object; in CSV it becomes a header and one line
+pub fn emit_object<S: Serialize>(format: OutputFormat, kind: &str, summary: &S) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    match format {
+        OutputFormat::Table => {}
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &tagged(kind, summary)?)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Jsonl => {
+            serde_json::to_writer(&mut out, &tagged(kind, summary)?)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Csv => {
+            let object = to_object(summary)?;
+            write_csv_line(&mut out, object.keys().map(String::as_str))?;
+            let cells = object.values().map(csv_cell).collect::<Vec<String>>();
+            write_csv_line(&mut out, cells.iter().map(String::as_str))?;
+        }
+    }
+    out.flush()
+}
+
+fn to_object<T: Serialize>(value: &T) -> io::Result<Map<String, Value>> {
+    match serde_json::to_value(value)? {
+        Value::Object(object) => Ok(object),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "reports must serialize to an object",
+        )),
+    }
+}
+
+// the object with "type" and "schema_version" in front of its own fields
+fn tagged<T: Serialize>(kind: &str, value: &T) -> io::Result<Map<String, Value>> {
+    let mut object = Map::new();
+    object.insert("type".to_string(), Value::from(kind));
+    object.insert("schema_version".to_string(), Value::from(SCHEMA_VERSION));
+    object.extend(to_object(value)?);
+    Ok(object)
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn write_csv_line<'a, W: Write>(
+    out: &mut W,
+    cells: impl Iterator<Item = &'a str>,
+) -> io::Result<()> {
+    let cells = cells
+        .map(|cell| {
+            if cell.contains([',', '"', '\n']) {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell.to_string()
+            }
+        })
+        .collect::<Vec<String>>();
+    writeln!(out, "{}", cells.join(","))
+}
@@ -0,0 +1,417 @@
+// this file keeps vaults open in a long-running server that answers lookups over a Unix socket or TCP, plus its client
+//
+// protocol: the client sends one request per line, the server answers each with one JSON line
+//   PING                                             -> {"ok":true,"command":"PING",...}
+//   VAULTS                                           -> one result per served vault
+//   PREFIX <hex prefix> [bits]                       -> every record in every vault whose hash starts with the prefix,
+//                                                       or an error if more than the server's --max-results match
+//   NONCE <decimal nonce>                            -> one result per vault: present or not, where, and if its hash matches
+//   CLOSEST <hex challenge> [numeric|xor] [top] [neighbours]
+//                                                    -> the `top` nearest records over all vaults (default xor, 1, 1),
+//                                                       `top` being at most the server's --max-results
+//   QUIT                                             -> closes the connection
+// every response has "ok", "command", "latency_us" (time spent answering, in microseconds) and "results",
+// or "ok": false and an "error"; a connection over the server's --max-connections gets one such error and is closed
+use crate::closest::{Distance, MAX_NEIGHBOURS};
+use crate::farm::Farm;
+use crate::lookup::{self, hash_to_string, nonce_to_decimal, SearchStrategy};
+use crate::prefix::{Prefix, PrefixFormat};
+use crate::RECORD_SIZE;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+// where the server listens / the client connects
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+// how much one request and the server as a whole may take on
+#[derive(Debug, Clone, Copy)]
+pub struct ServeLimits {
+    // most records a PREFIX or CLOSEST answer may hold
+    pub max_results: usize,
+    // most connections answered at once
+    pub max_connections: usize,
+}
+
+#[derive(Serialize)]
+struct Response {
+    ok: bool,
+    command: String,
+    latency_us: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// serves the plots of an open farm until the process is stopped; every bucket index is built before listening,
+// so no request pays for it
+pub fn serve(farm: Farm, endpoint: &Endpoint, limits: ServeLimits) -> io::Result<()> {
+    for plot in &farm.plots {
+        eprintln!(
+            "Serving {} ({} records, {} buckets indexed)",
+            plot.path.display(),
+            plot.footer.num_records,
+            plot.index()?.num_buckets()
+        );
+    }
+    let farm = Arc::new(farm);
+    let open_connections = Arc::new(AtomicUsize::new(0));
+
+    match endpoint {
+        Endpoint::Unix(socket_path) => {
+            // a socket file left behind by an earlier server would make bind fail, but anything else at that path
+            // is not ours to delete
+            match fs::symlink_metadata(socket_path) {
+                Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(socket_path)?,
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "{} exists and is not a socket, refusing to replace it",
+                            socket_path.display()
+                        ),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listener = UnixListener::bind(socket_path)?;
+            eprintln!("Listening on {}", socket_path.display());
+            for stream in listener.incoming() {
+                // one failed connection is the client's problem, not a reason to stop serving everyone else
+                let accepted = stream.and_then(|stream| Ok((stream.try_clone()?, stream)));
+                match accepted {
+                    Ok((reader, stream)) => {
+                        accept(&farm, &open_connections, limits, reader, stream)
+                    }
+                    Err(e) => eprintln!("Error accepting a connection: {}", e),
+                }
+            }
+        }
+        Endpoint::Tcp(address) => {
+            let listener = TcpListener::bind(address)?;
+            eprintln!("Listening on {}", listener.local_addr()?);
+            for stream in listener.incoming() {
+                // answers are small, so don't let Nagle hold them back waiting for more
+                let accepted = stream.and_then(|stream| {
+                    stream.set_nodelay(true)?;
+                    Ok((stream.try_clone()?, stream))
+                });
+                match accepted {
+                    Ok((reader, stream)) => {
+                        accept(&farm, &open_connections, limits, reader, stream)
+                    }
+                    Err(e) => eprintln!("Error accepting a connection: {}", e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// answers a new connection on its own thread, or turns it away with an error if too many are already open
+fn accept<R, W>(
+    farm: &Arc<Farm>,
+    open_connections: &Arc<AtomicUsize>,
+    limits: ServeLimits,
+    reader: R,
+    mut writer: W,
+) where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let already_open = open_connections.fetch_add(1, Ordering::SeqCst);
+    // gives the slot back however the connection ends, a panicking handler included
+    let slot = ConnectionSlot(Arc::clone(open_connections));
+    if already_open >= limits.max_connections {
+        drop(slot);
+        let response = Response {
+            ok: false,
+            command: String::new(),
+            latency_us: 0.0,
+            results: None,
+            error: Some(format!(
+                "server busy: {} connections already open",
+                limits.max_connections
+            )),
+        };
+        // the client is going away either way, so a failed write changes nothing
+        if let Ok(line) = serde_json::to_string(&response) {
+            let _ = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+        }
+        return;
+    }
+
+    let farm = Arc::clone(farm);
+    thread::spawn(move || {
+        let _slot = slot;
+        if let Err(e) = handle_connection(&farm, limits, BufReader::new(reader), writer) {
+            eprintln!("Connection closed with an error: {}", e);
+        }
+    });
+}
+
+// one taken connection slot, given back when dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_connection<R: BufRead, W: Write>(
+    farm: &Farm,
+    limits: ServeLimits,
+    reader: R,
+    writer: W,
+) -> io::Result<()> {
+    // each response goes out in one write instead of one per JSON token
+    let mut writer = io::BufWriter::new(writer);
+    for line in reader.lines() {
+        let line = line?;
+        let request = line.trim();
+        if request.is_empty() {
+            continue;
+        }
+
+        let start_request = Instant::now();
+        let command = request
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        if command == "QUIT" {
+            break;
+        }
+        let outcome = answer(farm, limits, &command, request);
+        let latency_us = start_request.elapsed().as_secs_f64() * 1e6;
+
+        let response = match outcome {
+            Ok(results) => Response {
+                ok: true,
+                command,
+                latency_us,
+                results: Some(results),
+                error: None,
+            },
+            Err(error) => Response {
+                ok: false,
+                command,
+                latency_us,
+                results: None,
+                error: Some(error),
+            },
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn answer(
+    farm: &Farm,
+    limits: ServeLimits,
+    command: &str,
+    request: &str,
+) -> Result<Vec<Value>, String> {
+    let args = request.split_whitespace().skip(1).collect::<Vec<&str>>();
+    match command {
+        "PING" => Ok(Vec::new()),
+        "VAULTS" => farm
+            .plots
+            .iter()
+            .enumerate()
+            .map(|(i, plot)| {
+                Ok(json!({
+                    "vault": i,
+                    "path": plot.path.display().to_string(),
+                    "k": plot.footer.k,
+                    "num_records": plot.footer.num_records,
+                    "num_buckets": plot.index().map_err(|e| e.to_string())?.num_buckets(),
+                    "plot_id": plot.id_hex(),
+                }))
+            })
+            .collect(),
+        "PREFIX" => {
+            let prefix = match args.as_slice() {
+                [prefix] => Prefix::parse(prefix, PrefixFormat::Hex, None),
+                [prefix, bits] => Prefix::parse(prefix, PrefixFormat::Hex, Some(parse(bits)?)),
+                _ => return Err("usage: PREFIX <hex prefix> [bits]".to_string()),
+            }
+            .map_err(|e| e.to_string())?;
+
+            let (records, _) = farm
+                .find_by_prefix_limited(&prefix, SearchStrategy::Interpolation, limits.max_results)
+                .map_err(|e| e.to_string())?;
+            if records.len() > limits.max_results {
+                return Err(format!(
+                    "more than {} records match, use a longer prefix",
+                    limits.max_results
+                ));
+            }
+            Ok(records
+                .iter()
+                .map(|found| {
+                    json!({
+                        "vault": found.plot,
+                        "plot_id": farm.plots[found.plot].id_hex(),
+                        "nonce": nonce_to_decimal(&found.record.nonce),
+                        "hash_hex": hash_to_string(&found.record.hash),
+                    })
+                })
+                .collect())
+        }
+        "NONCE" => {
+            let nonce = match args.as_slice() {
+                [nonce] => parse::<u64>(nonce)?,
+                _ => return Err("usage: NONCE <decimal nonce>".to_string()),
+            };
+
+            let mut results = Vec::new();
+            for (i, plot) in farm.plots.iter().enumerate() {
+                let found = lookup::find_by_nonce(&plot.file, &plot.footer, nonce)
+                    .map_err(|e| e.to_string())?;
+                results.push(json!({
+                    "vault": i,
+                    "plot_id": plot.id_hex(),
+                    "present": found.found.is_some(),
+                    "record_index": found.found.map(|(index, _)| index),
+                    "offset_bytes": found.found.map(|(index, _)| index * RECORD_SIZE as u64),
+                    "hash_matches": found.hash_matches(),
+                }));
+            }
+            Ok(results)
+        }
+        "CLOSEST" => {
+            if args.is_empty() || args.len() > 4 {
+                return Err(
+                    "usage: CLOSEST <hex challenge> [numeric|xor] [top] [neighbours]".to_string(),
+                );
+            }
+            let challenge =
+                Prefix::parse(args[0], PrefixFormat::Hex, None).map_err(|e| e.to_string())?;
+            let distance = match args.get(1) {
+                Some(name) => Distance::from_name(name)
+                    .ok_or_else(|| format!("unknown distance '{}'", name))?,
+                None => Distance::Xor,
+            };
+            let top = args.get(2).map_or(Ok(1), |top| parse::<usize>(top))?;
+            let neighbours = args.get(3).map_or(Ok(1), |n| parse::<u64>(n))?;
+            if neighbours > MAX_NEIGHBOURS {
+                return Err(format!(
+                    "neighbours {} is over the limit of {} buckets",
+                    neighbours, MAX_NEIGHBOURS
+                ));
+            }
+            if top > limits.max_results {
+                return Err(format!(
+                    "top {} is over this server's limit of {} results",
+                    top, limits.max_results
+                ));
+            }
+
+            let (matches, _, _) = farm
+                .find_closest(challenge.bytes(), distance, top, neighbours)
+                .map_err(|e| e.to_string())?;
+
+            Ok(matches
+                .iter()
+                .enumerate()
+                .map(|(rank, found)| {
+                    json!({
+                        "vault": found.plot,
+                        "plot_id": farm.plots[found.plot].id_hex(),
+                        "rank": rank + 1,
+                        "record_index": found.found.index,
+                        "nonce": nonce_to_decimal(&found.found.nonce),
+                        "hash_hex": hash_to_string(&found.found.hash),
+                        "distance_hex": hash_to_string(&found.found.distance),
+                    })
+                })
+                .collect())
+        }
+        _ => Err(format!(
+            "unknown command '{}' (expected PING, VAULTS, PREFIX, NONCE, CLOSEST or QUIT)",
+            command
+        )),
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("'{}' is not a valid number", value))
+}
+
+// sends each request to a running server and prints its JSON responses; the round trip of each is reported on stderr
+pub fn client(endpoint: &Endpoint, requests: &[String]) -> io::Result<()> {
+    match endpoint {
+        Endpoint::Unix(socket_path) => {
+            let stream = UnixStream::connect(socket_path)?;
+            send_requests(BufReader::new(stream.try_clone()?), stream, requests)
+        }
+        Endpoint::Tcp(address) => {
+            let stream = TcpStream::connect(address)?;
+            stream.set_nodelay(true)?;
+            send_requests(BufReader::new(stream.try_clone()?), stream, requests)
+        }
+    }
+}
+
+fn send_requests<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    requests: &[String],
+) -> io::Result<()> {
+    let mut response = String::new();
+    for request in requests {
+        let start_request = Instant::now();
+        writeln!(writer, "{}", request)?;
+        writer.flush()?;
+
+        response.clear();
+        if reader.read_line(&mut response)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection",
+            ));
+        }
+        let round_trip = start_request.elapsed();
+
+        print!("{}", response);
+        let server_latency = serde_json::from_str::<Value>(&response)
+            .ok()
+            .and_then(|value| value["latency_us"].as_f64());
+        match server_latency {
+            Some(latency_us) => eprintln!(
+                "{}: server {:.1} us, round trip {:.1} us",
+                request,
+                latency_us,
+                round_trip.as_secs_f64() * 1e6
+            ),
+            None => eprintln!(
+                "{}: round trip {:.1} us",
+                request,
+                round_trip.as_secs_f64() * 1e6
+            ),
+        }
+    }
+    writeln!(writer, "QUIT")?;
+    writer.flush()
+}
@@ -0,0 +1,295 @@
+// this file runs a local mock chain: every block interval it derives a challenge from the previous block, lets each
+// plot of a farm answer it through lookup, and keeps score of response times, wins and missed deadlines
+use crate::batch_lookup::LatencyStats;
+use crate::farm::Farm;
+use crate::lookup::hash_to_string;
+use crate::quality::{self, PlotQuality};
+use crate::report::{self, OutputFormat};
+use crate::HASH_SIZE;
+use blake3::Hasher;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct SimulationConfig {
+    pub blocks: u64,
+    pub block_interval: Duration, // time between two challenges; zero issues them back to back
+    pub deadline: Duration,       // answers slower than this are not accepted
+    pub difficulty: Option<f64>, // quality bits needed to win (default: log2 of the farm's records, rounded up)
+    pub seed: String, // genesis of the chain, so two runs with the same seed see the same challenges
+}
+
+// what one plot did over the whole run
+#[derive(Default)]
+struct PlotScore {
+    wins: u64,
+    qualified: u64, // answers reaching the difficulty, whether or not another plot beat them
+    missed_deadlines: u64,
+    latencies: Vec<Duration>,
+}
+
+#[derive(Serialize)]
+struct SimulationSummary {
+    farm: String,
+    num_plots: usize,
+    seed: String,
+    blocks: u64,
+    block_interval_ms: f64,
+    deadline_ms: f64,
+    difficulty_bits: f64,
+    blocks_won: u64,
+    win_rate: f64,
+    missed_deadlines: u64,
+    response_p50_us: f64,
+    response_p99_us: f64,
+    response_max_us: f64,
+    chain_head_hex: String,
+    duration_s: f64,
+}
+
+#[derive(Serialize, Default)]
+struct SimulationRow {
+    plot: String,
+    plot_id: String,
+    k: u32,
+    num_records: u64,
+    wins: u64,
+    win_rate: f64,
+    qualified: u64,
+    missed_deadlines: u64,
+    latency_p50_us: f64,
+    latency_p99_us: f64,
+    latency_max_us: f64,
+}
+
+pub fn simulate(
+    source: &str,
+    farm: &Farm,
+    config: &SimulationConfig,
+    format: OutputFormat,
+) -> io::Result<()> {
+    if config.blocks == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the simulation needs at least one block",
+        ));
+    }
+    let difficulty = config
+        .difficulty
+        .unwrap_or_else(|| (farm.num_records().max(1) as f64).log2().ceil());
+
+    // every bucket index is built up front, like a farmer's would be, so no block's latency pays for it
+    farm.plots
+        .par_iter()
+        .try_for_each(|plot| plot.index().map(|_| ()))?;
+
+    let mut previous_hash = *blake3::hash(config.seed.as_bytes()).as_bytes();
+    let mut scores = farm
+        .plots
+        .iter()
+        .map(|_| PlotScore::default())
+        .collect::<Vec<PlotScore>>();
+    let mut responses = Vec::with_capacity(config.blocks as usize);
+    let mut blocks_won = 0;
+    let mut missed_deadlines = 0;
+
+    let start_simulation = Instant::now();
+    for height in 1..=config.blocks {
+        let start_block = Instant::now();
+        let challenge = next_challenge(&previous_hash, height);
+
+        // every plot answers on its own thread, timed on its own, as separate disks would
+        let answers = farm
+            .plots
+            .par_iter()
+            .map(|plot| {
+                let start_answer = Instant::now();
+                let answer = quality::evaluate_plot(plot, &challenge)?;
+                Ok((answer, start_answer.elapsed()))
+            })
+            .collect::<io::Result<Vec<(Option<PlotQuality>, Duration)>>>()?;
+        let response = start_block.elapsed();
+        responses.push(response);
+        if response > config.deadline {
+            missed_deadlines += 1;
+        }
+
+        // the best qualifying answer extends the chain whatever its latency, so the next challenge only depends
+        // on the seed and the vaults; the deadline decides who scores the win, not what the chain looks like
+        let mut best: Option<&PlotQuality> = None;
+        let mut winner: Option<(usize, &PlotQuality)> = None;
+        for (i, (answer, latency)) in answers.iter().enumerate() {
+            let score = &mut scores[i];
+            score.latencies.push(*latency);
+            let on_time = *latency <= config.deadline;
+            if !on_time {
+                score.missed_deadlines += 1;
+            }
+            let Some(answer) = answer else { continue };
+            if !answer.reaches(difficulty) {
+                continue;
+            }
+            // ties go to the earlier plot, so every run of the same chain picks the same answer
+            if best.is_none_or(|best| answer.quality > best.quality) {
+                best = Some(answer);
+            }
+            if !on_time {
+                continue;
+            }
+            score.qualified += 1;
+            if winner.is_none_or(|(_, best)| answer.quality > best.quality) {
+                winner = Some((i, answer));
+            }
+        }
+        if let Some((i, _)) = winner {
+            scores[i].wins += 1;
+            blocks_won += 1;
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&previous_hash);
+        hasher.update(&challenge);
+        match best {
+            Some(answer) => {
+                hasher.update(&answer.nonce.to_be_bytes());
+                hasher.update(&answer.hash);
+            }
+            None => {
+                hasher.update(b"no proof");
+            }
+        }
+        previous_hash = *hasher.finalize().as_bytes();
+
+        if format.is_table() {
+            match winner {
+                Some((i, answer)) => println!(
+                    "Block {:>6} | challenge {}.. | won by {} with quality {:.3} bits | response {:?}{}",
+                    height,
+                    &hash_to_string(&challenge)[..16],
+                    farm.plots[i].id_hex(),
+                    answer.quality,
+                    response,
+                    if response > config.deadline { " (missed deadline)" } else { "" }
+                ),
+                None => println!(
+                    "Block {:>6} | challenge {}.. | no winning proof | response {:?}{}",
+                    height,
+                    &hash_to_string(&challenge)[..16],
+                    response,
+                    if response > config.deadline { " (missed deadline)" } else { "" }
+                ),
+            }
+        }
+
+        // wait for the next block, like a real chain would
+        if let Some(remaining) = config.block_interval.checked_sub(start_block.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+    let duration = start_simulation.elapsed();
+
+    let micros = |latency: Duration| latency.as_secs_f64() * 1e6;
+    let plot_stats = scores
+        .iter()
+        .map(|score| {
+            LatencyStats::from_latencies(score.latencies.clone())
+                .expect("every plot answered every block")
+        })
+        .collect::<Vec<LatencyStats>>();
+    let rows = farm
+        .plots
+        .iter()
+        .zip(&scores)
+        .zip(&plot_stats)
+        .map(|((plot, score), stats)| SimulationRow {
+            plot: plot.path.display().to_string(),
+            plot_id: plot.id_hex(),
+            k: plot.footer.k,
+            num_records: plot.footer.num_records,
+            wins: score.wins,
+            win_rate: score.wins as f64 / config.blocks as f64,
+            qualified: score.qualified,
+            missed_deadlines: score.missed_deadlines,
+            latency_p50_us: micros(stats.p50),
+            latency_p99_us: micros(stats.p99),
+            latency_max_us: micros(stats.max),
+        })
+        .collect::<Vec<SimulationRow>>();
+    let response_stats =
+        LatencyStats::from_latencies(responses).expect("at least one block was simulated");
+
+    if !format.is_table() {
+        let summary = SimulationSummary {
+            farm: source.to_string(),
+            num_plots: farm.plots.len(),
+            seed: config.seed.clone(),
+            blocks: config.blocks,
+            block_interval_ms: config.block_interval.as_secs_f64() * 1e3,
+            deadline_ms: config.deadline.as_secs_f64() * 1e3,
+            difficulty_bits: difficulty,
+            blocks_won,
+            win_rate: blocks_won as f64 / config.blocks as f64,
+            missed_deadlines,
+            response_p50_us: micros(response_stats.p50),
+            response_p99_us: micros(response_stats.p99),
+            response_max_us: micros(response_stats.max),
+            chain_head_hex: hash_to_string(&previous_hash[..HASH_SIZE].try_into().unwrap()),
+            duration_s: duration.as_secs_f64(),
+        };
+        return report::emit_rows(format, "simulation", &summary, "plots", &rows);
+    }
+
+    println!(
+        "{} blocks at difficulty {} bits in {:.3} seconds: {} won by the farm ({:.1}%), {} missed deadlines",
+        config.blocks,
+        difficulty,
+        duration.as_secs_f64(),
+        blocks_won,
+        blocks_won as f64 / config.blocks as f64 * 100.0,
+        missed_deadlines
+    );
+    println!(
+        "Farm response: p50 {:?}, p99 {:?}, max {:?} (deadline {:?})",
+        response_stats.p50, response_stats.p99, response_stats.max, config.deadline
+    );
+    println!(
+        "{:<16} | {:<3} | {:<12} | {:<6} | {:<8} | {:<9} | {:<6} | {:<12} | {:<12} | {:<12}",
+        "Plot ID", "k", "Records", "Wins", "Win rate", "Qualified", "Missed", "p50", "p99", "Max"
+    );
+    println!("{}", "-".repeat(124));
+    for (row, stats) in rows.iter().zip(&plot_stats) {
+        println!(
+            "{} | {:<3} | {:<12} | {:<6} | {:>7.1}% | {:<9} | {:<6} | {:<12} | {:<12} | {:<12}",
+            row.plot_id,
+            row.k,
+            row.num_records,
+            row.wins,
+            row.win_rate * 100.0,
+            row.qualified,
+            row.missed_deadlines,
+            format!("{:?}", stats.p50),
+            format!("{:?}", stats.p99),
+            format!("{:?}", stats.max)
+        );
+    }
+    println!(
+        "Chain head after {} blocks: {} (seed '{}')",
+        config.blocks,
+        hash_to_string(&previous_hash[..HASH_SIZE].try_into().unwrap()),
+        config.seed
+    );
+    Ok(())
+}
+
+// the challenge of block `height` is BLAKE3 of the previous block hash and the height
+fn next_challenge(previous_hash: &[u8; 32], height: u64) -> [u8; HASH_SIZE] {
+    let mut hasher = Hasher::new();
+    hasher.update(previous_hash);
+    hasher.update(&height.to_be_bytes());
+    hasher.finalize().as_bytes()[..HASH_SIZE]
+        .try_into()
+        .unwrap()
+}
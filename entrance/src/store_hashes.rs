@@ -1,22 +1,23 @@
 // this file writes the hashes to disk using multiple threads
-use crate::{Record, OUTPUT_FOLDER, RECORD_SIZE};
+use crate::{Record, RECORD_SIZE};
 use dashmap::DashMap;
 use std::fs::OpenOptions;
 use std::io::{self, BufWriter, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::Path;
 use std::sync::RwLock;
 
+const SEQUENTIAL_WRITE_BUFFER: usize = 8 * 1024 * 1024;
+
 pub fn flush_to_disk(
     records: &DashMap<usize, Vec<Record>>,
-    filename: &str,
+    path: &Path,
     offsets: &RwLock<Vec<usize>>,
 ) -> io::Result<()> {
-    let path: PathBuf = PathBuf::from(OUTPUT_FOLDER).join(filename);
     let file = OpenOptions::new()
         .write(true)
         .create(true)
-        .open(path)
-        .expect("Error opening file");
+        .truncate(false)
+        .open(path)?;
 
     let mut writer = BufWriter::new(&file);
     let mut offsets = offsets.write().unwrap(); // Acquire read lock on offsets
@@ -43,3 +44,38 @@ pub fn flush_to_disk(
     writer.flush()?;
     Ok(())
 }
+
+// writes every bucket front to back in one sequential pass; used when the whole vault fits in memory
+pub fn write_vault_sequentially(
+    records: &DashMap<usize, Vec<Record>>,
+    path: &Path,
+    num_buckets: usize,
+    bucket_size: usize,
+) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut writer = BufWriter::with_capacity(SEQUENTIAL_WRITE_BUFFER, file);
+    let padding = [0u8; RECORD_SIZE];
+
+    for prefix in 0..num_buckets {
+        let mut written = 0;
+        if let Some(bucket) = records.get(&prefix) {
+            for record in bucket.iter().take(bucket_size) {
+                writer.write_all(&record.nonce)?;
+                writer.write_all(&record.hash)?;
+            }
+            written = bucket.len().min(bucket_size);
+        }
+
+        // keep every bucket at its fixed offset, even if it came up short
+        for _ in written..bucket_size {
+            writer.write_all(&padding)?;
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+// this file marks finished vaults with a footer and makes sure readers only ever open finished vaults
+use crate::RECORD_SIZE;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const FOOTER_SIZE: usize = 2 * RECORD_SIZE; // keeps the footer aligned to whole records
+const VAULT_MAGIC: [u8; 8] = *b"VAULT76\0";
+const VAULT_VERSION: u32 = 1;
+const PARTIAL_EXTENSION: &str = "partial";
+
+// written after the last record once sorting and syncing are done; a vault without it was never finished
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultFooter {
+    magic: [u8; 8],
+    version: u32,
+    pub k: u32,
+    pub prefix_size: u32,
+    pub sorted: bool,
+    pub num_buckets: u64,
+    pub bucket_size: u64, // records per disk bucket
+    pub num_records: u64,
+    pub plot_id: u64,
+}
+
+impl VaultFooter {
+    pub fn new(
+        k: u32,
+        prefix_size: u32,
+        sorted: bool,
+        num_buckets: usize,
+        bucket_size: usize,
+        num_records: usize,
+        plot_id: u64,
+    ) -> Self {
+        Self {
+            magic: VAULT_MAGIC,
+            version: VAULT_VERSION,
+            k,
+            prefix_size,
+            sorted,
+            num_buckets: num_buckets as u64,
+            bucket_size: bucket_size as u64,
+            num_records: num_records as u64,
+            plot_id,
+        }
+    }
+
+    // size in bytes of the record region in front of the footer
+    pub fn data_size(&self) -> u64 {
+        self.num_records * RECORD_SIZE as u64
+    }
+}
+
+// the name a vault is written under until it is finalized, e.g. vault.bin -> vault.bin.partial
+pub fn partial_path(vault_path: &Path) -> PathBuf {
+    let mut file_name = vault_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(PARTIAL_EXTENSION);
+    vault_path.with_file_name(file_name)
+}
+
+// appends the footer to the partial vault, syncs it, then renames it into place in one atomic step
+pub fn finalize_vault(partial: &Path, vault_path: &Path, footer: &VaultFooter) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(partial)?;
+    file.seek(SeekFrom::Start(footer.data_size()))?;
+    file.write_all(&encode_footer(footer)?)?;
+    file.set_len(footer.data_size() + FOOTER_SIZE as u64)?;
+    file.sync_all()?;
+
+    fs::rename(partial, vault_path)?;
+
+    // persist the rename itself, otherwise a crash could bring the partial name back
+    if let Some(dir) = vault_path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// opens a finished vault, refusing anything that was never finalized
+pub fn open_vault(vault_path: &Path) -> io::Result<(File, VaultFooter)> {
+    if vault_path
+        .extension()
+        .is_some_and(|ext| ext == PARTIAL_EXTENSION)
+    {
+        return Err(not_finalized(vault_path));
+    }
+
+    let mut file = File::open(vault_path)?;
+    let footer = read_footer(&mut file).ok_or_else(|| not_finalized(vault_path))?;
+
+    if footer.version != VAULT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} was written with vault format version {}, expected {}",
+                vault_path.display(),
+                footer.version,
+                VAULT_VERSION
+            ),
+        ));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok((file, footer))
+}
+
+fn read_footer(file: &mut File) -> Option<VaultFooter> {
+    let file_size = file.metadata().ok()?.len();
+    if file_size < FOOTER_SIZE as u64 {
+        return None;
+    }
+
+    let mut buffer = [0u8; FOOTER_SIZE];
+    file.seek(SeekFrom::Start(file_size - FOOTER_SIZE as u64))
+        .ok()?;
+    file.read_exact(&mut buffer).ok()?;
+
+    let footer: VaultFooter = bincode::deserialize(&buffer).ok()?;
+    if footer.magic != VAULT_MAGIC || footer.data_size() + FOOTER_SIZE as u64 != file_size {
+        return None;
+    }
+    Some(footer)
+}
+
+fn encode_footer(footer: &VaultFooter) -> io::Result<[u8; FOOTER_SIZE]> {
+    let encoded =
+        bincode::serialize(footer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut buffer = [0u8; FOOTER_SIZE];
+    buffer[..encoded.len()].copy_from_slice(&encoded);
+    Ok(buffer)
+}
+
+fn not_finalized(vault_path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} was never finalized (generation or sorting did not finish), refusing to use it",
+            vault_path.display()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_RECORDS: usize = 256;
+
+    // a scratch directory of its own for every test, removed again when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "vault76-vault-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn footer() -> VaultFooter {
+        VaultFooter::new(8, 6, true, 64, 4, NUM_RECORDS, 0xfeedface)
+    }
+
+    // a partial vault full of records, as generation leaves it before the footer
+    fn write_partial(vault_path: &Path) -> PathBuf {
+        let partial = partial_path(vault_path);
+        fs::write(&partial, vec![0xab; NUM_RECORDS * RECORD_SIZE]).unwrap();
+        partial
+    }
+
+    #[test]
+    fn partial_names_keep_the_vault_name() {
+        assert_eq!(
+            partial_path(Path::new("out/vault.bin")),
+            PathBuf::from("out/vault.bin.partial")
+        );
+    }
+
+    #[test]
+    fn finalized_vaults_open_with_their_footer() {
+        let dir = TestDir::new("finalized");
+        let vault_path = dir.0.join("vault.bin");
+        let partial = write_partial(&vault_path);
+
+        finalize_vault(&partial, &vault_path, &footer()).unwrap();
+        assert!(!partial.exists());
+        assert_eq!(
+            fs::metadata(&vault_path).unwrap().len(),
+            footer().data_size() + FOOTER_SIZE as u64
+        );
+
+        let (_, opened) = open_vault(&vault_path).unwrap();
+        assert_eq!(opened.k, 8);
+        assert!(opened.sorted);
+        assert_eq!(opened.num_records, NUM_RECORDS as u64);
+        assert_eq!(opened.plot_id, 0xfeedface);
+    }
+
+    #[test]
+    fn partial_vaults_are_refused() {
+        let dir = TestDir::new("partial");
+        let partial = write_partial(&dir.0.join("vault.bin"));
+
+        let error = open_vault(&partial).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("never finalized"), "{}", error);
+    }
+
+    #[test]
+    fn vaults_without_a_valid_footer_are_refused() {
+        let dir = TestDir::new("footer");
+        let vault_path = dir.0.join("vault.bin");
+
+        // renamed without a footer, as if finalizing had been skipped
+        fs::rename(write_partial(&vault_path), &vault_path).unwrap();
+        assert!(open_vault(&vault_path).is_err());
+
+        // smaller than a footer
+        fs::write(&vault_path, [0u8; FOOTER_SIZE - 1]).unwrap();
+        assert!(open_vault(&vault_path).is_err());
+
+        // a footer whose record count doesn't match the file, as after a truncated copy
+        let partial = write_partial(&vault_path);
+        finalize_vault(&partial, &vault_path, &footer()).unwrap();
+        let bytes = fs::read(&vault_path).unwrap();
+        fs::write(&vault_path, &bytes[RECORD_SIZE..]).unwrap();
+        assert!(open_vault(&vault_path).is_err());
+    }
+}
@@ -0,0 +1,650 @@
+// this file checks that a finished vault is laid out correctly: order, bucket placement, duplicates and padding
+use crate::hash_generator::extract_prefix;
+use crate::memory_budget::MemoryBudget;
+use crate::progress_tracker::ProgressTracker;
+use crate::{vault, NONCE_SIZE, RECORD_SIZE};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const VERIFY_CHUNK_SIZE: usize = 1024 * 1024; // bytes read per positional read while scanning a bucket
+const NONCE_ENTRY_SIZE: usize = 16; // a nonce and the byte offset of its record, as two little-endian u64s
+const MAX_RUN_BUFFER: usize = 64 * 1024; // read buffer per spilled run while merging
+
+type Hash = [u8; RECORD_SIZE - NONCE_SIZE];
+
+// how many records failed one check, and the byte offset of the first one
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Check {
+    pub count: u64,
+    pub first_offset: Option<u64>,
+}
+
+impl Check {
+    fn record(&mut self, offset: u64) {
+        self.count += 1;
+        if self.first_offset.is_none() {
+            self.first_offset = Some(offset);
+        }
+    }
+
+    // folds in the result of another bucket, keeping whichever first offset comes earlier
+    fn merge(&mut self, other: Check) {
+        self.count += other.count;
+        self.first_offset = match (self.first_offset, other.first_offset) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.first_offset {
+            Some(offset) => write!(f, "{} (first at byte offset {})", self.count, offset),
+            None => write!(f, "0"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub expected_records: u64,
+    pub records_read: u64,
+    pub out_of_order: Check, // hash smaller than the one before it, inside a bucket
+    pub boundary_out_of_order: Check, // first hash of a bucket smaller than the last hash of the previous bucket
+    pub misplaced: Check,             // hash prefix doesn't match the bucket the record sits in
+    pub duplicate_hashes: Check,
+    pub duplicate_nonces: Check,
+    pub padding_records: Check, // all-zero records
+    pub duration: f64,          // in seconds
+    pub throughput: f64,        // in MB/s
+}
+
+impl VerifyReport {
+    pub fn is_valid(&self) -> bool {
+        self.records_read == self.expected_records
+            && self.out_of_order.count == 0
+            && self.boundary_out_of_order.count == 0
+            && self.misplaced.count == 0
+            && self.duplicate_hashes.count == 0
+            && self.duplicate_nonces.count == 0
+            && self.padding_records.count == 0
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Records: {} read, {} expected",
+            self.records_read, self.expected_records
+        );
+        println!("Out of order inside buckets: {}", self.out_of_order);
+        println!(
+            "Out of order across bucket boundaries: {}",
+            self.boundary_out_of_order
+        );
+        println!("Records in the wrong bucket: {}", self.misplaced);
+        println!("Duplicate hashes: {}", self.duplicate_hashes);
+        println!("Duplicate nonces: {}", self.duplicate_nonces);
+        println!("Zero-padding records: {}", self.padding_records);
+        println!(
+            "Verified in {:.2} seconds: {:.2} MB/s",
+            self.duration, self.throughput
+        );
+    }
+
+    pub fn summary(&self, path: &Path) -> VerifySummary {
+        VerifySummary {
+            vault: path.display().to_string(),
+            valid: self.is_valid(),
+            expected_records: self.expected_records,
+            records_read: self.records_read,
+            out_of_order: self.out_of_order.count,
+            out_of_order_first_offset_bytes: self.out_of_order.first_offset,
+            boundary_out_of_order: self.boundary_out_of_order.count,
+            boundary_out_of_order_first_offset_bytes: self.boundary_out_of_order.first_offset,
+            misplaced: self.misplaced.count,
+            misplaced_first_offset_bytes: self.misplaced.first_offset,
+            duplicate_hashes: self.duplicate_hashes.count,
+            duplicate_hashes_first_offset_bytes: self.duplicate_hashes.first_offset,
+            duplicate_nonces: self.duplicate_nonces.count,
+            duplicate_nonces_first_offset_bytes: self.duplicate_nonces.first_offset,
+            padding_records: self.padding_records.count,
+            padding_records_first_offset_bytes: self.padding_records.first_offset,
+            duration_s: self.duration,
+            throughput_mb_per_s: self.throughput,
+        }
+    }
+}
+
+// the report flattened into one level, so it fits a single CSV line
+#[derive(Serialize)]
+pub struct VerifySummary {
+    vault: String,
+    valid: bool,
+    expected_records: u64,
+    records_read: u64,
+    out_of_order: u64,
+    out_of_order_first_offset_bytes: Option<u64>,
+    boundary_out_of_order: u64,
+    boundary_out_of_order_first_offset_bytes: Option<u64>,
+    misplaced: u64,
+    misplaced_first_offset_bytes: Option<u64>,
+    duplicate_hashes: u64,
+    duplicate_hashes_first_offset_bytes: Option<u64>,
+    duplicate_nonces: u64,
+    duplicate_nonces_first_offset_bytes: Option<u64>,
+    padding_records: u64,
+    padding_records_first_offset_bytes: Option<u64>,
+    duration_s: f64,
+    throughput_mb_per_s: f64,
+}
+
+// what one bucket looked like, so the buckets can be checked against each other afterwards
+#[derive(Default)]
+struct BucketSummary {
+    records_read: u64,
+    out_of_order: Check,
+    misplaced: Check,
+    duplicate_hashes: Check,
+    padding_records: Check,
+    first_hash: Option<(Hash, u64)>, // smallest non-padding hash and its offset
+    last_hash: Option<Hash>,
+}
+
+// the (nonce, offset) pairs of one bucket, sorted; kept in memory when every bucket's pairs fit in the memory
+// budget together, otherwise spilled to a range of the scratch file
+enum NonceRun {
+    Memory(Vec<(u64, u64)>),
+    Spilled { start: u64, entries: u64 },
+}
+
+// the scratch file holding spilled runs, removed once verify is done with it (or fails)
+struct NonceScratch {
+    path: PathBuf,
+    file: File,
+    end: AtomicU64,
+}
+
+impl NonceScratch {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            end: AtomicU64::new(0),
+        })
+    }
+
+    // appends one sorted run; every bucket reserves its own range, so runs are written in parallel
+    fn spill(&self, nonces: &[(u64, u64)]) -> io::Result<NonceRun> {
+        let run_bytes = (nonces.len() * NONCE_ENTRY_SIZE) as u64;
+        let start = self.end.fetch_add(run_bytes, Ordering::SeqCst);
+        let mut position = start;
+        for chunk in nonces.chunks(MAX_RUN_BUFFER / NONCE_ENTRY_SIZE) {
+            let bytes = chunk
+                .iter()
+                .flat_map(|&(nonce, offset)| {
+                    let mut entry = [0u8; NONCE_ENTRY_SIZE];
+                    entry[..8].copy_from_slice(&nonce.to_le_bytes());
+                    entry[8..].copy_from_slice(&offset.to_le_bytes());
+                    entry
+                })
+                .collect::<Vec<u8>>();
+            self.file.write_all_at(&bytes, position)?;
+            position += bytes.len() as u64;
+        }
+        Ok(NonceRun::Spilled {
+            start,
+            entries: nonces.len() as u64,
+        })
+    }
+}
+
+impl Drop for NonceScratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// reads one run back in order, a buffer at a time for spilled runs
+struct RunCursor<'a> {
+    memory: std::vec::IntoIter<(u64, u64)>,
+    file: Option<&'a File>,
+    position: u64,
+    end: u64,
+    buffer_entries: u64,
+}
+
+impl<'a> RunCursor<'a> {
+    fn new(run: NonceRun, scratch: Option<&'a NonceScratch>, buffer_entries: u64) -> Self {
+        match run {
+            NonceRun::Memory(nonces) => Self {
+                memory: nonces.into_iter(),
+                file: None,
+                position: 0,
+                end: 0,
+                buffer_entries,
+            },
+            NonceRun::Spilled { start, entries } => Self {
+                memory: Vec::new().into_iter(),
+                file: scratch.map(|scratch| &scratch.file),
+                position: start,
+                end: start + entries * NONCE_ENTRY_SIZE as u64,
+                buffer_entries,
+            },
+        }
+    }
+
+    fn next(&mut self) -> io::Result<Option<(u64, u64)>> {
+        if let Some(entry) = self.memory.next() {
+            return Ok(Some(entry));
+        }
+        let file = match self.file {
+            Some(file) if self.position < self.end => file,
+            _ => return Ok(None),
+        };
+        let len = (self.end - self.position).min(self.buffer_entries * NONCE_ENTRY_SIZE as u64);
+        let mut bytes = vec![0u8; len as usize];
+        file.read_exact_at(&mut bytes, self.position)?;
+        self.position += len;
+        self.memory = bytes
+            .chunks_exact(NONCE_ENTRY_SIZE)
+            .map(|entry| {
+                (
+                    u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                    u64::from_le_bytes(entry[8..].try_into().unwrap()),
+                )
+            })
+            .collect::<Vec<(u64, u64)>>()
+            .into_iter();
+        Ok(self.memory.next())
+    }
+}
+
+// verifies every bucket in parallel on the rayon pool, then checks the bucket boundaries and nonces across buckets
+// without an expected count, the footer's record count is expected
+// the duplicate nonce check holds at most `memory_limit` bytes of nonces; beyond that they are sorted per bucket,
+// spilled to a scratch file next to the vault and merged back
+pub fn verify_vault(
+    path: &Path,
+    expected_count: Option<usize>,
+    memory_limit: usize,
+    show_progress: bool,
+) -> io::Result<VerifyReport> {
+    let (file, footer) = vault::open_vault(path)?;
+    if !footer.sorted {
+        return Err(io::Error::other(format!(
+            "{} was finalized without sorting",
+            path.display()
+        )));
+    }
+
+    let start_verify = Instant::now();
+    let num_records = footer.num_records;
+    let bucket_size = footer.bucket_size.max(1);
+    let num_buckets = num_records.div_ceil(bucket_size);
+
+    let tracker = if show_progress {
+        let tracker =
+            ProgressTracker::new(num_records, num_buckets as usize, Duration::from_secs(2));
+        tracker.set_stage("[VERIFY]");
+        Some(tracker)
+    } else {
+        None
+    };
+
+    let budget = MemoryBudget::new(memory_limit);
+    let scratch = if num_records as usize * NONCE_ENTRY_SIZE > budget.total() {
+        Some(NonceScratch::create(path.with_extension("nonces"))?)
+    } else {
+        None
+    };
+
+    let (summaries, runs): (Vec<BucketSummary>, Vec<NonceRun>) = (0..num_buckets)
+        .into_par_iter()
+        .map(|bucket| {
+            let first_record = bucket * bucket_size;
+            let records = bucket_size.min(num_records - first_record);
+            let _permit = budget.acquire(records as usize * NONCE_ENTRY_SIZE);
+            let (summary, mut nonces) = verify_bucket(
+                &file,
+                bucket,
+                first_record,
+                records,
+                footer.prefix_size as usize,
+            )?;
+            nonces.sort_unstable();
+            let run = match scratch {
+                Some(ref scratch) => scratch.spill(&nonces)?,
+                None => NonceRun::Memory(nonces),
+            };
+            if let Some(ref tracker) = tracker {
+                tracker.update_records_processed(records);
+                tracker.increment_flushes(1);
+            }
+            Ok((summary, run))
+        })
+        .collect::<io::Result<Vec<(BucketSummary, NonceRun)>>>()?
+        .into_iter()
+        .unzip();
+
+    let mut report = VerifyReport {
+        expected_records: expected_count.map_or(num_records, |count| count as u64),
+        ..Default::default()
+    };
+
+    let mut last_hash: Option<Hash> = None;
+    for summary in summaries {
+        report.records_read += summary.records_read;
+        report.out_of_order.merge(summary.out_of_order);
+        report.misplaced.merge(summary.misplaced);
+        report.duplicate_hashes.merge(summary.duplicate_hashes);
+        report.padding_records.merge(summary.padding_records);
+
+        // sort order (and duplicates) across the boundary to the previous non-empty bucket
+        if let (Some(previous), Some((first, offset))) = (last_hash, summary.first_hash) {
+            if first < previous {
+                report.boundary_out_of_order.record(offset);
+            } else if first == previous {
+                report.duplicate_hashes.merge(Check {
+                    count: 1,
+                    first_offset: Some(offset),
+                });
+            }
+        }
+        if summary.last_hash.is_some() {
+            last_hash = summary.last_hash;
+        }
+    }
+
+    report.duplicate_nonces = duplicate_nonces(runs, scratch.as_ref(), &budget)?;
+
+    report.duration = start_verify.elapsed().as_secs_f64();
+    report.throughput = footer.data_size() as f64 / 1024.0 / 1024.0 / report.duration;
+
+    if let Some(ref tracker) = tracker {
+        tracker.report_progress();
+    }
+
+    Ok(report)
+}
+
+// merges the sorted runs of every bucket in (nonce, offset) order, so repeats of a nonce come out next to each
+// other with the earliest copy first; every later copy counts as a duplicate
+fn duplicate_nonces(
+    runs: Vec<NonceRun>,
+    scratch: Option<&NonceScratch>,
+    budget: &MemoryBudget,
+) -> io::Result<Check> {
+    let buffer_entries = (budget.total() / runs.len().max(1))
+        .clamp(NONCE_ENTRY_SIZE, MAX_RUN_BUFFER)
+        / NONCE_ENTRY_SIZE;
+    let mut cursors = runs
+        .into_iter()
+        .map(|run| RunCursor::new(run, scratch, buffer_entries as u64))
+        .collect::<Vec<RunCursor>>();
+
+    let mut heap = BinaryHeap::with_capacity(cursors.len());
+    for (run_index, cursor) in cursors.iter_mut().enumerate() {
+        if let Some((nonce, offset)) = cursor.next()? {
+            heap.push(Reverse((nonce, offset, run_index)));
+        }
+    }
+
+    let mut duplicates = Check::default();
+    let mut previous = None;
+    while let Some(Reverse((nonce, offset, run_index))) = heap.pop() {
+        if previous == Some(nonce) {
+            duplicates.merge(Check {
+                count: 1,
+                first_offset: Some(offset),
+            });
+        }
+        previous = Some(nonce);
+        if let Some((nonce, offset)) = cursors[run_index].next()? {
+            heap.push(Reverse((nonce, offset, run_index)));
+        }
+    }
+    Ok(duplicates)
+}
+
+// scans one bucket with positional reads, so every bucket can share the same file handle;
+// returns the bucket's summary and the (nonce, offset) of every record holding a hash
+fn verify_bucket(
+    file: &File,
+    bucket: u64,
+    first_record: u64,
+    records: u64,
+    prefix_size: usize,
+) -> io::Result<(BucketSummary, Vec<(u64, u64)>)> {
+    let mut summary = BucketSummary::default();
+    let mut nonces = Vec::with_capacity(records as usize);
+
+    let records_per_chunk = (VERIFY_CHUNK_SIZE / RECORD_SIZE) as u64;
+    let mut buffer = vec![0u8; VERIFY_CHUNK_SIZE];
+    let mut chunk_start = 0;
+
+    while chunk_start < records {
+        let chunk_records = records_per_chunk.min(records - chunk_start);
+        let chunk = &mut buffer[..chunk_records as usize * RECORD_SIZE];
+        file.read_exact_at(chunk, (first_record + chunk_start) * RECORD_SIZE as u64)?;
+
+        for (i, record) in chunk.chunks_exact(RECORD_SIZE).enumerate() {
+            let offset = (first_record + chunk_start + i as u64) * RECORD_SIZE as u64;
+            summary.records_read += 1;
+
+            // padding is reported on its own and left out of the other checks
+            if record.iter().all(|&byte| byte == 0) {
+                summary.padding_records.record(offset);
+                continue;
+            }
+
+            let mut hash: Hash = [0u8; RECORD_SIZE - NONCE_SIZE];
+            hash.copy_from_slice(&record[NONCE_SIZE..]);
+
+            if extract_prefix(&hash, prefix_size) != bucket {
+                summary.misplaced.record(offset);
+            }
+
+            match summary.last_hash {
+                Some(last_hash) if hash < last_hash => summary.out_of_order.record(offset),
+                Some(last_hash) if hash == last_hash => summary.duplicate_hashes.record(offset),
+                _ => {}
+            }
+            if summary.first_hash.is_none() {
+                summary.first_hash = Some((hash, offset));
+            }
+            summary.last_hash = Some(hash);
+
+            let nonce = record[..NONCE_SIZE]
+                .iter()
+                .fold(0u64, |acc, &b| acc * 256 + b as u64);
+            nonces.push((nonce, offset));
+        }
+
+        chunk_start += chunk_records;
+    }
+
+    Ok((summary, nonces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_generator::generate_hash;
+    use crate::vault::VaultFooter;
+
+    const PREFIX_SIZE: u32 = 2;
+    const NUM_BUCKETS: usize = 1 << PREFIX_SIZE;
+    const BUCKET_SIZE: usize = 64;
+    const NUM_RECORDS: usize = NUM_BUCKETS * BUCKET_SIZE;
+    const SPILLING_LIMIT: usize = 1024; // less than the nonces of the vault, so they go to a scratch file
+
+    type RawRecord = [u8; RECORD_SIZE];
+
+    // a scratch directory of its own for every test, removed again when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "vault76-verify-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        // finalizes `records` into a vault of full buckets and verifies it within `memory_limit` bytes
+        fn verify(&self, records: &[RawRecord], memory_limit: usize) -> VerifyReport {
+            let vault_path = self.0.join("vault.bin");
+            let partial = vault::partial_path(&vault_path);
+            fs::write(&partial, records.concat()).unwrap();
+            let footer = VaultFooter::new(
+                20,
+                PREFIX_SIZE,
+                true,
+                NUM_BUCKETS,
+                BUCKET_SIZE,
+                NUM_RECORDS,
+                0xbadc0de,
+            );
+            vault::finalize_vault(&partial, &vault_path, &footer).unwrap();
+            let report = verify_vault(&vault_path, None, memory_limit, false).unwrap();
+            assert!(!vault_path.with_extension("nonces").exists());
+            fs::remove_file(&vault_path).unwrap();
+            report
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // a correct vault: every bucket full of its own hashes, sorted, with unique nonces
+    fn valid_records() -> Vec<RawRecord> {
+        let mut buckets = vec![Vec::new(); NUM_BUCKETS];
+        let mut nonce = 0;
+        while buckets.iter().any(|bucket| bucket.len() < BUCKET_SIZE) {
+            let (bucket, record) = generate_hash(nonce, PREFIX_SIZE as usize);
+            if buckets[bucket as usize].len() < BUCKET_SIZE {
+                let mut bytes = [0u8; RECORD_SIZE];
+                bytes[..NONCE_SIZE].copy_from_slice(&record.nonce);
+                bytes[NONCE_SIZE..].copy_from_slice(&record.hash);
+                buckets[bucket as usize].push(bytes);
+            }
+            nonce += 1;
+        }
+        for bucket in buckets.iter_mut() {
+            bucket.sort_unstable_by(|a, b| a[NONCE_SIZE..].cmp(&b[NONCE_SIZE..]));
+        }
+        buckets.concat()
+    }
+
+    fn offset(index: usize) -> Option<u64> {
+        Some((index * RECORD_SIZE) as u64)
+    }
+
+    // every check but the one named found nothing
+    fn assert_only(report: &VerifyReport, failed: &Check) {
+        assert!(!report.is_valid());
+        assert_eq!(report.records_read, NUM_RECORDS as u64);
+        let checks = [
+            &report.out_of_order,
+            &report.boundary_out_of_order,
+            &report.misplaced,
+            &report.duplicate_hashes,
+            &report.duplicate_nonces,
+            &report.padding_records,
+        ];
+        let others: u64 = checks.iter().map(|check| check.count).sum::<u64>() - failed.count;
+        assert_eq!(others, 0, "{:?}", report);
+    }
+
+    #[test]
+    fn a_correct_vault_is_valid() {
+        let dir = TestDir::new("valid");
+        for memory_limit in [1 << 20, SPILLING_LIMIT] {
+            let report = dir.verify(&valid_records(), memory_limit);
+            assert!(report.is_valid(), "{:?}", report);
+            assert_eq!(report.records_read, NUM_RECORDS as u64);
+        }
+    }
+
+    #[test]
+    fn a_record_in_the_wrong_bucket_is_found() {
+        let dir = TestDir::new("misplaced");
+        let mut records = valid_records();
+        // just below the first hash of bucket 1, so it is still in order at the end of bucket 0
+        let mut hash: Hash = records[BUCKET_SIZE][NONCE_SIZE..].try_into().unwrap();
+        let last = hash.iter().rposition(|&byte| byte != 0).unwrap();
+        hash[last] -= 1;
+        hash[last + 1..].fill(0xff);
+        records[BUCKET_SIZE - 1][NONCE_SIZE..].copy_from_slice(&hash);
+
+        let report = dir.verify(&records, 1 << 20);
+        assert_eq!(report.misplaced.count, 1);
+        assert_eq!(report.misplaced.first_offset, offset(BUCKET_SIZE - 1));
+        assert_only(&report, &report.misplaced);
+    }
+
+    #[test]
+    fn a_duplicate_nonce_is_found_in_memory_and_spilled() {
+        let dir = TestDir::new("nonces");
+        let mut records = valid_records();
+        let nonce: [u8; NONCE_SIZE] = records[3][..NONCE_SIZE].try_into().unwrap();
+        records[2 * BUCKET_SIZE + 5][..NONCE_SIZE].copy_from_slice(&nonce);
+
+        for memory_limit in [1 << 20, SPILLING_LIMIT] {
+            let report = dir.verify(&records, memory_limit);
+            assert_eq!(report.duplicate_nonces.count, 1, "limit {}", memory_limit);
+            assert_eq!(
+                report.duplicate_nonces.first_offset,
+                offset(2 * BUCKET_SIZE + 5),
+                "limit {}",
+                memory_limit
+            );
+            assert_only(&report, &report.duplicate_nonces);
+        }
+    }
+
+    #[test]
+    fn a_record_out_of_order_is_found() {
+        let dir = TestDir::new("order");
+        let mut records = valid_records();
+        records.swap(BUCKET_SIZE + 10, BUCKET_SIZE + 11);
+
+        let report = dir.verify(&records, 1 << 20);
+        assert_eq!(report.out_of_order.count, 1);
+        assert_eq!(report.out_of_order.first_offset, offset(BUCKET_SIZE + 11));
+        assert_only(&report, &report.out_of_order);
+    }
+
+    #[test]
+    fn zero_padding_is_found() {
+        let dir = TestDir::new("padding");
+        let mut records = valid_records();
+        records[NUM_RECORDS - 1] = [0u8; RECORD_SIZE];
+
+        let report = dir.verify(&records, 1 << 20);
+        assert_eq!(report.padding_records.count, 1);
+        assert_eq!(report.padding_records.first_offset, offset(NUM_RECORDS - 1));
+        assert_only(&report, &report.padding_records);
+    }
+}